@@ -0,0 +1,50 @@
+//! Embeds the git commit, build time and compiler version into the
+//! version string shown by `--version`.
+use std::{
+    env,
+    path::Path,
+    process::Command,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Run a command and return its trimmed stdout, if it succeeded.
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
+
+fn main() {
+    let commit = command_output("git", &["rev-parse", "--short", "HEAD"])
+        .unwrap_or_else(|| "unknown".into());
+
+    // honour SOURCE_DATE_EPOCH so reproducible builds stay reproducible
+    let build_time = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+        .unwrap_or_else(SystemTime::now);
+    let build_time = humantime::format_rfc3339_seconds(build_time);
+
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let rustc_version = command_output(&rustc, &["--version"])
+        .and_then(|v| v.split_whitespace().nth(1).map(String::from))
+        .unwrap_or_else(|| "unknown".into());
+
+    println!(
+        "cargo:rustc-env=WOL_PROXY_VERSION={} ({} {} rustc {})",
+        env::var("CARGO_PKG_VERSION").unwrap(),
+        commit,
+        build_time,
+        rustc_version
+    );
+
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    for path in [".git/HEAD", ".git/refs/heads", ".git/packed-refs"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
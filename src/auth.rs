@@ -0,0 +1,62 @@
+//! Token preamble for authenticating plain TCP connections.
+//!
+//! A client proves it knows the shared token by sending, before anything
+//! else, the 32 byte HMAC-SHA256 under the token of the current unix
+//! minute (`floor(unix_time / 60)` as a big-endian `u64`).
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Length of the preamble.
+pub const TAG_LEN: usize = 32;
+
+fn hmac(token: &[u8], minute: u64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(token).expect("HMAC accepts keys of any length");
+    mac.update(&minute.to_be_bytes());
+    mac
+}
+
+/// The preamble a client should send at unix time `now` (seconds).
+pub fn tag(token: &[u8], now: u64) -> [u8; TAG_LEN] {
+    hmac(token, now / 60).finalize().into_bytes().into()
+}
+
+/// Check a preamble received at unix time `now`. The minutes either side
+/// of the current one are accepted too, to allow for clock skew.
+pub fn verify(token: &[u8], tag: &[u8], now: u64) -> bool {
+    let minute = now / 60;
+    [minute.saturating_sub(1), minute, minute + 1]
+        .iter()
+        .any(|&m| hmac(token, m).verify_slice(tag).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &[u8] = b"auth token";
+    /// The start of a minute
+    const NOW: u64 = 1_700_000_040;
+
+    #[test]
+    fn accepts_current_and_neighbouring_minutes() {
+        let preamble = tag(TOKEN, NOW);
+        assert!(verify(TOKEN, &preamble, NOW));
+        assert!(verify(TOKEN, &preamble, NOW + 59));
+        assert!(verify(TOKEN, &preamble, NOW + 60));
+        assert!(verify(TOKEN, &preamble, NOW - 60));
+    }
+
+    #[test]
+    fn rejects_stale_or_wrong_preamble() {
+        let preamble = tag(TOKEN, NOW);
+        assert!(!verify(TOKEN, &preamble, NOW + 120));
+        assert!(!verify(TOKEN, &preamble, NOW - 61));
+        assert!(!verify(b"other token", &preamble, NOW));
+        assert!(!verify(TOKEN, &preamble[..TAG_LEN - 1], NOW));
+        let mut flipped = preamble;
+        flipped[0] ^= 1;
+        assert!(!verify(TOKEN, &flipped, NOW));
+    }
+}
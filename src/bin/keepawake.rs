@@ -1,19 +1,54 @@
 //! A simple TCP proxy that holds a wake lock during the connection
 //! and for a configurable time afterwards.
+use std::future::Future;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use clap::Parser;
+use std::task::{ready, Context, Poll};
+use clap::{CommandFactory, Parser, Subcommand};
 use keepawake::KeepAwake;
 use tokio::sync::Notify;
 use tokio::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::{TcpListener, TcpStream};
 use anyhow::Result;
+use tracing::{debug, error, info, warn, Instrument};
+use wol_proxy::cli::{self, CompletionsArgs, ManArgs};
+use wol_proxy::duration::{parse_duration, parse_interval};
+use wol_proxy::logging::{kind, LogArgs};
+use wol_proxy::net::{canonical_peer, show_client};
+use wol_proxy::pidfile::PidFile;
+use wol_proxy::privs::{self, PrivilegeArgs};
+use wol_proxy::runtime;
+use wol_proxy::shutdown;
+
+/// Ids for the connection spans, unique for the life of the process
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
 
 #[derive(Parser)]
-#[command(version, about = "TCP proxy to keep the machine awake")]
+#[command(name = "keepawake", version = env!("WOL_PROXY_VERSION"), about = "TCP proxy to keep the machine awake")]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true, arg_required_else_help = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    proxy: Option<ProxyArgs>,
+
+    // kept out of ProxyArgs, as clap can't tell whether an optional
+    // flattened struct was given when it contains another one
+    #[command(flatten)]
+    privileges: PrivilegeArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+#[derive(clap::Args)]
+struct ProxyArgs {
     #[clap(short, long)]
     /// Address of the target
     target: String,
@@ -22,31 +57,107 @@ struct Args {
     /// Listen address to bind to
     bind: String,
 
-    #[clap(long, default_value = "300")]
-    /// Number of seconds to keep the wake lock active after the last
-    /// connection is closed
-    timeout: u64
+    #[clap(long, default_value = "300", value_parser = parse_duration)]
+    /// How long to keep the wake lock active after the last connection is
+    /// closed (e.g. "300", "5m")
+    timeout: Duration,
+
+    #[clap(long, default_value = "0")]
+    /// Only hold the wake lock for a connection once this many bytes have
+    /// passed through it, so health checks and port scans don't keep the
+    /// machine awake
+    min_connection_bytes: u64,
+
+    #[clap(long, value_parser = parse_interval)]
+    /// Open and close a connection to the target this often, so devices
+    /// that sleep when their network goes quiet stay awake
+    keepalive_interval: Option<Duration>,
+
+    #[clap(long)]
+    /// Write the process ID to this file, removing it again on shutdown
+    pid_file: Option<PathBuf>,
+
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    /// Run on N worker threads rather than one, so large transfers run in
+    /// parallel
+    worker_threads: Option<u32>,
+
+    #[clap(long)]
+    /// Replace the last octet of IPv4 client addresses, and the last 64
+    /// bits of IPv6 ones, with "x" in log output
+    mask_client_ips: bool,
+
+    #[clap(long)]
+    /// Accept connections and log what would be done, without taking the
+    /// wakelock or forwarding any data
+    dry_run: bool,
+}
+
+#[derive(clap::Args)]
+struct LocalArgs {
+    #[clap(long, default_value = "inhibiting sleep")]
+    /// Reason given to the OS for the wake lock
+    reason: String,
+
+    #[clap(long)]
+    /// Keep the display on as well
+    display: bool,
+
+    #[clap(long)]
+    /// Don't prevent the machine from idling
+    no_idle: bool,
+
+    #[clap(long)]
+    /// Don't prevent the machine from sleeping
+    no_sleep: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Hold the wake lock until SIGINT/SIGTERM, without proxying anything
+    Local(LocalArgs),
+
+    /// Print a shell completion script
+    GenerateCompletions(CompletionsArgs),
+
+    /// Print the man page
+    GenerateMan(ManArgs),
 }
 
 /// Supervisor thread that waits for the last connection to close.
-async fn supervisor(active_connections: Arc<AtomicU64>, ac_notify: Arc<Notify>, timeout: Duration) -> Result<()> {
+///
+/// `last_trigger` holds the ID of the connection that most recently
+/// opened or closed, so state transitions can be attributed to it.
+async fn supervisor(active_connections: Arc<AtomicU64>, ac_notify: Arc<Notify>, last_trigger: Arc<AtomicU64>, timeout: Duration, dry_run: bool) -> Result<()> {
     let mut _awake: Option<KeepAwake> = None;
     let mut locked = false;
+    let mut locked_at = std::time::Instant::now();
     loop {
         // Wait for notification of a state change
         ac_notify.notified().await;
         // If there are active connections, ensure the wakelock is held
         if active_connections.load(Ordering::SeqCst) > 0 {
             if !locked {
-                println!("acquiring wakelock");
-                _awake = Some(keepawake::Builder::default()
-                    .display(false)
-                    .idle(true)
-                    .sleep(true)
-                    .reason("active TCP proxy connection")
-                    .app_reverse_domain("pw.karel.wol-proxy")
-                    .create()?);
+                info!(
+                    name: kind::WAKELOCK,
+                    previous_state = "unlocked",
+                    new_state = "locked",
+                    active_connections = active_connections.load(Ordering::SeqCst),
+                    trigger = last_trigger.load(Ordering::SeqCst),
+                    "{}",
+                    if dry_run { "Would acquire wakelock" } else { "acquiring wakelock" }
+                );
+                if !dry_run {
+                    _awake = Some(keepawake::Builder::default()
+                        .display(false)
+                        .idle(true)
+                        .sleep(true)
+                        .reason("active TCP proxy connection")
+                        .app_reverse_domain("pw.karel.wol-proxy")
+                        .create()?);
+                }
                 locked = true;
+                locked_at = std::time::Instant::now();
             }
         } else {
             // No active connections, wait for the timeout before releasing the wakelock
@@ -56,79 +167,335 @@ async fn supervisor(active_connections: Arc<AtomicU64>, ac_notify: Arc<Notify>,
             };
 
             // Double-check active connections after waiting to avoid a race condition
-            if active_connections.load(Ordering::SeqCst) == 0 {
-                if locked {
-                    println!("releasing wakelock");
-                    // we have to do this cause there's a bug in keepawake
-                    drop(_awake);
-                    _awake = None;
-                    // _awake = Some(keepawake::Builder::default()
-                    //     .display(false)
-                    //     .idle(false)
-                    //     .sleep(false)
-                    //     .reason("no active TCP proxy connection")
-                    //     .app_reverse_domain("pw.karel.wol-proxy")
-                    //     .create()?);
-                    // drop(_awake);
-                    // _awake = Some(keepawake::Builder::default()
-                    //     .display(false)
-                    //     .idle(false)
-                    //     .sleep(false)
-                    //     .reason("no active TCP proxy connection")
-                    //     .app_reverse_domain("pw.karel.wol-proxy")
-                    //     .create()?);
-                    locked = false;
-                }
+            if active_connections.load(Ordering::SeqCst) == 0 && locked {
+                info!(
+                    name: kind::WAKELOCK,
+                    previous_state = "locked",
+                    new_state = "unlocked",
+                    active_connections = active_connections.load(Ordering::SeqCst),
+                    held_for_ms = locked_at.elapsed().as_millis() as u64,
+                    trigger = last_trigger.load(Ordering::SeqCst),
+                    "{}",
+                    if dry_run { "Would release wakelock" } else { "releasing wakelock" }
+                );
+                // we have to do this cause there's a bug in keepawake
+                drop(_awake);
+                _awake = None;
+                // _awake = Some(keepawake::Builder::default()
+                //     .display(false)
+                //     .idle(false)
+                //     .sleep(false)
+                //     .reason("no active TCP proxy connection")
+                //     .app_reverse_domain("pw.karel.wol-proxy")
+                //     .create()?);
+                // drop(_awake);
+                // _awake = Some(keepawake::Builder::default()
+                //     .display(false)
+                //     .idle(false)
+                //     .sleep(false)
+                //     .reason("no active TCP proxy connection")
+                //     .app_reverse_domain("pw.karel.wol-proxy")
+                //     .create()?);
+                locked = false;
             }
         }
     }
 }
 
-async fn handle_client(mut stream: TcpStream, target_addr: &SocketAddr) -> Result<()> {
+/// Client stream that calls `on_threshold` once `remaining` more bytes
+/// have passed through it, in either direction.
+struct Metered<S, F> {
+    inner: S,
+    remaining: u64,
+    on_threshold: Option<F>,
+}
+
+impl<S, F: FnOnce()> Metered<S, F> {
+    fn count(&mut self, n: usize) {
+        self.remaining = self.remaining.saturating_sub(n as u64);
+        if self.remaining == 0 {
+            if let Some(on_threshold) = self.on_threshold.take() {
+                on_threshold();
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin, F: FnOnce() + Unpin> AsyncRead for Metered<S, F> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let result = ready!(Pin::new(&mut self.inner).poll_read(cx, buf));
+        let n = buf.filled().len() - before;
+        self.count(n);
+        Poll::Ready(result)
+    }
+}
+
+impl<S: AsyncWrite + Unpin, F: FnOnce() + Unpin> AsyncWrite for Metered<S, F> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        let result = ready!(Pin::new(&mut self.inner).poll_write(cx, buf));
+        if let Ok(n) = result {
+            self.count(n);
+        }
+        Poll::Ready(result)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Connect to the target and hang up again every `interval`, forever. The
+/// point is only the traffic, so failures are just logged.
+async fn heartbeat(target_addr: SocketAddr, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match tokio::time::timeout(Duration::from_secs(5), TcpStream::connect(target_addr)).await {
+            Ok(Ok(_)) => debug!("heartbeat connection to {} made", target_addr),
+            Ok(Err(e)) => warn!("heartbeat connection to {} failed: {}", target_addr, e),
+            Err(_) => warn!("heartbeat connection to {} timed out", target_addr),
+        }
+    }
+}
+
+/// Proxy a connection to the target, returning the number of bytes sent
+/// to and received from the target.
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, target_addr: &SocketAddr) -> Result<(u64, u64)> {
     let mut target = TcpStream::connect(&target_addr).await?;
-    tokio::io::copy_bidirectional(&mut stream, &mut target).await?;
+    let transferred = tokio::io::copy_bidirectional(&mut stream, &mut target).await?;
+    Ok(transferred)
+}
+
+/// Hold a wake lock until asked to stop, for sleep inhibition on the machine
+/// the service itself runs on.
+async fn run_local(args: LocalArgs) -> Result<()> {
+    let take = || Ok(keepawake::Builder::default()
+        .display(args.display)
+        .idle(!args.no_idle)
+        .sleep(!args.no_sleep)
+        .reason(&args.reason)
+        .app_reverse_domain("pw.karel.wol-proxy")
+        .create()?);
+    hold(take, &args.reason, shutdown::signal()).await
+}
+
+/// Take the lock with `take` and hold it until `stop` completes.
+async fn hold<L>(take: impl FnOnce() -> Result<L>, reason: &str, stop: impl Future<Output = Result<()>>) -> Result<()> {
+    let _lock = take()?;
+    info!(reason, "holding wakelock until shutdown");
+    stop.await?;
+    info!("releasing wakelock");
     Ok(())
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
-    // parse command line arguments
-    let args = Args::parse();
+async fn run_proxy(args: ProxyArgs, privileges: &PrivilegeArgs) -> Result<()> {
     let target_addr = SocketAddr::from_str(&args.target)?;
 
     let notify = Arc::new(Notify::new());
     let active_connections = Arc::new(AtomicU64::new(0));
+    let last_trigger = Arc::new(AtomicU64::new(0));
 
     // Spawn supervisor thread to manage wakelock
-    // (must be on its own thread bc of how wakelocks work)
-    // let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
-    tokio::spawn(supervisor(active_connections.clone(), notify.clone(), Duration::from_secs(args.timeout)));
+    // (must be on its own thread bc of how wakelocks work: on Windows the
+    // lock belongs to the thread that took it, and with --worker-threads
+    // a tokio task could be moved to another one before releasing it)
+    let supervisor_span = tracing::info_span!("supervisor");
+    let supervised = supervisor(active_connections.clone(), notify.clone(), last_trigger.clone(), args.timeout, args.dry_run)
+        .instrument(supervisor_span);
+    let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+    std::thread::Builder::new().name("supervisor".to_string()).spawn(move || {
+        if let Err(e) = rt.block_on(supervised) {
+            error!("wakelock supervisor stopped: {}", e);
+        }
+    })?;
+    if let Some(interval) = args.keepalive_interval.filter(|_| !args.dry_run) {
+        tokio::spawn(heartbeat(target_addr, interval).instrument(tracing::info_span!("heartbeat")));
+    }
 
     // main server loop: accept new connections and forward them to the target
     let listener = TcpListener::bind(&args.bind).await?;
+    privs::drop_privileges(privileges)?;
+    // written as the user the proxy runs as, so it can be removed again
+    let _pid_file = match args.pid_file {
+        Some(path) => {
+            let pid_file = PidFile::create(path)?;
+            if let Some(pid) = &pid_file.stale {
+                warn!("replaced a stale pid file (pid {})", pid);
+            }
+            Some(pid_file)
+        }
+        None => None,
+    };
+
+    // stop cleanly on SIGINT/SIGTERM so the pid file gets removed
+    let shutdown = shutdown::signal();
+    tokio::pin!(shutdown);
     loop {
-        let (stream, addr) = listener.accept().await?;
+        let (stream, addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            result = &mut shutdown => {
+                info!("shutting down");
+                return result;
+            }
+        };
+        let addr = show_client(canonical_peer(addr), args.mask_client_ips);
+        let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::SeqCst);
+        // every log line about this connection carries its id and peer
+        let span = tracing::info_span!("connection", id, peer = %addr);
 
         // clone pointers for lifetime purposes
         let aconn_clone = active_connections.clone();
         let notify_clone = notify.clone();
-        println!("Accepted connection from {}", addr);
+        let trigger_clone = last_trigger.clone();
+        span.in_scope(|| info!(name: kind::CONNECTION, "Accepted connection from {}", addr));
         // spawn actual proxy task
         tokio::spawn(async move {
-            // Increment active connection (only notify supervisor if this is the first connection to open)
-            if aconn_clone.fetch_add(1, Ordering::SeqCst) == 0 {
-                notify_clone.notify_waiters();
-            }
+            // Increment active connection (only notify supervisor if this is the first connection to open),
+            // once it has carried --min-connection-bytes
+            let counted = Arc::new(AtomicBool::new(false));
+            let engage = {
+                let (aconn_clone, notify_clone, trigger_clone, counted) = (aconn_clone.clone(), notify_clone.clone(), trigger_clone.clone(), counted.clone());
+                move || {
+                    counted.store(true, Ordering::SeqCst);
+                    trigger_clone.store(id, Ordering::SeqCst);
+                    if aconn_clone.fetch_add(1, Ordering::SeqCst) == 0 {
+                        notify_clone.notify_waiters();
+                    }
+                }
+            };
+            let mut stream = Metered { inner: stream, remaining: args.min_connection_bytes, on_threshold: Some(engage) };
+            // with no minimum, this counts the connection straight away
+            stream.count(0);
 
             // proxy
-            match handle_client(stream, &target_addr).await {
-                Ok(()) => println!("connection finished successfully"),
-                Err(e) => eprintln!("proxy error: {}", e),
+            if args.dry_run {
+                info!("Would proxy {} → {}", addr, target_addr);
+                // hold the connection open (discarding its data) so the
+                // supervisor sees a realistic session
+                let _ = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await;
+            } else {
+                match handle_client(stream, &target_addr).await {
+                    Ok((sent, received)) => info!(
+                        "connection from {} finished successfully ({} bytes sent, {} bytes received)",
+                        addr, sent, received
+                    ),
+                    Err(e) => error!("proxy error: {}", e),
+                }
             }
             // Decrement active connection (only notify supervisor if this was the last connection to close)
+            if !counted.load(Ordering::SeqCst) {
+                return;
+            }
+            trigger_clone.store(id, Ordering::SeqCst);
             if aconn_clone.fetch_sub(1, Ordering::SeqCst) == 1 {
                 notify_clone.notify_waiters();
             }
-        });
+        }.instrument(span));
+    }
+}
+
+fn main() -> Result<()> {
+    // parse command line arguments
+    let args = Args::parse();
+    let worker_threads = args.proxy.as_ref().and_then(|proxy| proxy.worker_threads);
+    runtime::build(worker_threads)?.block_on(run(args))
+}
+
+async fn run(args: Args) -> Result<()> {
+    args.log.init("keepawake")?;
+    match args.command {
+        Some(Command::Local(local_args)) => run_local(local_args).await,
+        Some(Command::GenerateCompletions(completions_args)) => cli::generate_completions(&mut Args::command(), &completions_args),
+        Some(Command::GenerateMan(man_args)) => cli::generate_man(
+            Args::command(),
+            &man_args,
+            &[(
+                "keepawake -t 127.0.0.1:22 -b 0.0.0.0:2222 --timeout 10m",
+                "Forward port 2222 to the local SSH server and keep the machine awake until 10 minutes after the last connection closes.",
+            )],
+            &["wol"],
+        ),
+        None => run_proxy(args.proxy.expect("clap requires the proxy arguments"), &args.privileges).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Stands in for the OS wake lock: 1 while it is held, 2 once released.
+    struct FakeLock(Arc<AtomicUsize>);
+
+    impl FakeLock {
+        fn take(state: Arc<AtomicUsize>) -> Result<Self> {
+            state.store(1, Ordering::SeqCst);
+            Ok(Self(state))
+        }
+    }
+
+    impl Drop for FakeLock {
+        fn drop(&mut self) {
+            self.0.store(2, Ordering::SeqCst);
+        }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn local_holds_lock_until_shutdown() {
+        let state = Arc::new(AtomicUsize::new(0));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let take = { let state = state.clone(); move || FakeLock::take(state) };
+        let daemon = tokio::spawn(hold(take, "testing", async { Ok(stopped.await?) }));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(state.load(Ordering::SeqCst), 1);
+        stop.send(()).unwrap();
+        daemon.await.unwrap().unwrap();
+        assert_eq!(state.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn keepalive_interval_must_be_positive() {
+        let args = |interval| Args::try_parse_from(["keepawake", "-t", "127.0.0.1:9", "-b", "127.0.0.1:0", "--keepalive-interval", interval]);
+        assert!(args("0").is_err());
+        let interval = args("30s").unwrap().proxy.unwrap().keepalive_interval;
+        assert_eq!(interval, Some(Duration::from_secs(30)));
+    }
+
+    /// A client connection that engages the supervisor through `counted`
+    /// once `min_bytes` have passed, as in `run_proxy`.
+    fn metered(min_bytes: u64, counted: &Arc<AtomicBool>) -> (Metered<tokio::io::DuplexStream, impl FnOnce() + Unpin>, tokio::io::DuplexStream) {
+        let (client, peer) = tokio::io::duplex(64);
+        let counted = counted.clone();
+        let mut stream = Metered { inner: client, remaining: min_bytes, on_threshold: Some(move || counted.store(true, Ordering::SeqCst)) };
+        stream.count(0);
+        (stream, peer)
+    }
+
+    #[tokio::test]
+    async fn zero_byte_connection_not_counted() {
+        use tokio::io::AsyncWriteExt;
+
+        let counted = Arc::new(AtomicBool::new(false));
+        let (mut stream, peer) = metered(1, &counted);
+        // connect and hang up again, like a port scan
+        drop(peer);
+        tokio::io::copy(&mut stream, &mut tokio::io::sink()).await.unwrap();
+        assert!(!counted.load(Ordering::SeqCst));
+
+        let (mut stream, mut peer) = metered(1, &counted);
+        peer.write_all(b"x").await.unwrap();
+        drop(peer);
+        tokio::io::copy(&mut stream, &mut tokio::io::sink()).await.unwrap();
+        assert!(counted.load(Ordering::SeqCst));
+
+        // without a minimum, every connection counts straight away
+        let counted = Arc::new(AtomicBool::new(false));
+        let _connection = metered(0, &counted);
+        assert!(counted.load(Ordering::SeqCst));
+    }
+}
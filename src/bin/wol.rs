@@ -1,120 +1,5839 @@
 //! A simple program to intercept incoming TCP connections and send a
 //! wake-on-lan packet to the real server, then transparently proxy once
 //! the server has woken up.
-use anyhow::{bail, Result};
-use clap::Parser;
-use ping_rs::PingOptions;
+use anyhow::{anyhow, bail, Result};
+use chrono::{Local, NaiveTime, Timelike};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use cron::Schedule;
+use futures::future::select_ok;
+use hickory_resolver::TokioResolver;
+use ping_rs::{PingError, PingOptions};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
 use std::{
-    net::{IpAddr, SocketAddr, SocketAddrV4},
+    collections::{HashMap, VecDeque},
+    io::IsTerminal,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
     str::FromStr,
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{lookup_host, TcpListener, TcpSocket, TcpStream, UdpSocket},
+    sync::oneshot,
+};
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+#[cfg(feature = "socks5")]
+use tokio_socks::tcp::Socks5Stream;
+use tracing::{debug, error, info, warn, Instrument};
+#[cfg(all(feature = "tui", unix))]
+use wol_proxy::dashboard::Dashboard;
+#[cfg(feature = "tls")]
+use wol_proxy::tls;
+use wol_proxy::{
+    auth,
+    cli::{self, CompletionsArgs, ManArgs},
+    control,
+    duration::{parse_duration, parse_interval},
+    events::{Event, EventLog, EventStream},
+    layer2,
+    learn::Traffic,
+    logging::{kind, LogArgs},
+    mac::{check_unicast, format_mac, interface_mac, neighbor_mac, parse_mac},
+    net::{canonical_peer, show_client},
+    pidfile::PidFile,
+    privs::{self, PrivilegeArgs},
+    relay::{self, Verifier, WakeRequest, WakeResponse},
+    runtime, shutdown, srv,
+    transfer::{ByteCounts, Counted},
 };
-use tokio::net::{TcpListener, TcpStream};
-use wake_on_lan;
 
 #[derive(Parser)]
+#[command(
+    name = "wol",
+    display_name = "wol-proxy",
+    version = env!("WOL_PROXY_VERSION"),
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    arg_required_else_help = true
+)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    proxy: Option<ProxyArgs>,
+
+    // kept out of ProxyArgs, as clap can't tell whether an optional
+    // flattened struct was given when it contains another one
+    #[command(flatten)]
+    ping: PingArgs,
+
+    #[command(flatten)]
+    privileges: PrivilegeArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+#[derive(Clone, clap::Args)]
+struct ProxyArgs {
     #[clap(short, long)]
     /// The MAC address of the server
     mac: String,
 
-    #[clap(short, long)]
-    /// The target address (ip:port) of the server
-    target: String,
+    #[clap(short, long, required_unless_present = "target_srv")]
+    /// The target address (host:port) of the server
+    target: Option<String>,
+
+    #[clap(long, value_name = "NAME", conflicts_with = "target")]
+    /// Find the target through this DNS SRV record (e.g.
+    /// "_ssh._tcp.example.com") instead of --target
+    target_srv: Option<String>,
+
+    #[clap(long, default_value = "60", value_parser = parse_duration, requires = "target_srv")]
+    /// How often to look the SRV record up again (0 to only look it up at
+    /// startup)
+    srv_refresh: Duration,
+
+    #[clap(long, value_name = "HOST:PORT", requires = "fallback_mac")]
+    /// A standby server to wake and proxy to instead when the target
+    /// doesn't wake up in time
+    fallback_target: Option<String>,
+
+    #[clap(long, value_name = "MAC", requires = "fallback_target")]
+    /// The MAC address of the --fallback-target server
+    fallback_mac: Option<String>,
+
+    #[clap(short, long, value_parser = parse_name)]
+    /// Name to refer to the server by in log messages (letters, digits and
+    /// hyphens)
+    name: Option<String>,
+
+    #[clap(long, default_value = "5", value_parser = parse_duration)]
+    /// How long to wait for the target hostname to resolve
+    resolve_timeout: Duration,
+
+    #[clap(long, default_value = "30", value_parser = parse_duration)]
+    /// How long to wait for the connection to the server once it is up
+    connect_timeout: Duration,
+
+    #[clap(long, default_value = "3")]
+    /// How many times to retry connecting to the server once it is up, in
+    /// case the service hasn't started yet
+    connect_retry: u32,
+
+    #[clap(long, default_value = "2", value_parser = parse_duration)]
+    /// How long to wait between connection retries
+    connect_retry_delay: Duration,
+
+    #[clap(long)]
+    #[cfg_attr(feature = "socks5", clap(conflicts_with = "socks5"))]
+    /// Never connect to the server from a recently used source port, for
+    /// NAT firewalls that stall connections reusing a port still in
+    /// TIME_WAIT
+    reuse_port_avoidance: bool,
+
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(0..=6))]
+    /// Set SO_PRIORITY to N (0-6) on client and server connections, so
+    /// Linux traffic control handles their packets ahead of lower
+    /// priority traffic on the same queue
+    so_priority: Option<u32>,
+
+    #[clap(long, value_parser = parse_duration)]
+    /// How long after the server is up to keep retrying the connection,
+    /// separate from the time it gets to wake up. When given, this rather
+    /// than --connect-retry decides when to stop retrying.
+    service_ready_timeout: Option<Duration>,
+
+    #[clap(long)]
+    /// Only count the service as ready once it has sent a banner after
+    /// connecting (SSH, SMTP, FTP...). The banner is passed on to the
+    /// client.
+    confirm_banner: bool,
+
+    #[clap(long, value_name = "REGEX", value_parser = parse_regex, requires = "confirm_banner")]
+    /// Also require the banner to match this regular expression
+    banner_regex: Option<Regex>,
+
+    #[clap(
+        long,
+        value_name = "BYTES",
+        default_value_t = 256,
+        requires = "confirm_banner"
+    )]
+    /// Most banner bytes to read while checking it
+    banner_bytes: usize,
+
+    #[clap(long, default_value = "3", value_parser = parse_duration, requires = "confirm_banner")]
+    /// How long the banner may take to arrive after connecting
+    banner_timeout: Duration,
 
     #[clap(short, long)]
     /// The address to listen on
     bind: String,
 
-    #[clap(long, default_value = "15")]
-    /// Maximum time to wait for the server to wake up in seconds
-    timeout: u64,
+    #[clap(long, default_value = "15", value_parser = parse_duration)]
+    /// Maximum time to wait for the server to wake up (e.g. "15", "90s",
+    /// "2m")
+    timeout: Duration,
+
+    #[clap(long, default_value = "0", value_parser = parse_duration)]
+    /// How long to wait after sending the magic packet before probing the
+    /// server, for machines that don't answer anything during POST. Not
+    /// counted towards --timeout.
+    pre_ping_delay: Duration,
+
+    #[clap(
+        long,
+        alias = "ping-success-threshold",
+        default_value = "1",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    /// Number of consecutive ping replies required before the server is
+    /// considered awake
+    confirm_count: u32,
+
+    #[clap(long)]
+    /// Maximum number of times to wake the server within any one hour. A
+    /// --fallback-target has the same number to itself
+    max_wakes_per_hour: Option<usize>,
+
+    #[clap(long, value_name = "N")]
+    /// After N connections in a row fail, refuse new ones for
+    /// --circuit-breaker-reset, then let a single one through to see if the
+    /// server has recovered
+    circuit_breaker_threshold: Option<u32>,
+
+    #[clap(long, default_value = "60", value_parser = parse_duration, requires = "circuit_breaker_threshold")]
+    /// How long to refuse connections once the circuit breaker trips
+    circuit_breaker_reset: Duration,
+
+    #[clap(long, value_name = "HH:MM-HH:MM", value_parser = parse_hours)]
+    /// Only accept connections during this window of local time (may be
+    /// repeated; windows can run past midnight). Without any, connections
+    /// are accepted at all hours.
+    allow_hours: Vec<HourWindow>,
+
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    /// Send at most N magic packets per minute, holding back any beyond
+    /// that until the limit allows them
+    wol_rate_limit: Option<u32>,
+
+    #[clap(long, value_name = "D", requires = "wol_rate_limit")]
+    /// Hold back at most D magic packets; when more are waiting, the one
+    /// that has waited longest is dropped
+    wol_queue_depth: Option<usize>,
+
+    #[clap(long, default_value = "0", value_parser = parse_duration)]
+    /// After a magic packet goes out, don't send another the same way for
+    /// this long; connections arriving meanwhile just wait for the server
+    wol_cooldown: Duration,
+
+    #[cfg(feature = "socks5")]
+    #[clap(long)]
+    /// Reach the server through this SOCKS5 proxy (host:port). The
+    /// readiness check then becomes a CONNECT through the proxy instead
+    /// of a ping.
+    socks5: Option<String>,
+
+    #[cfg(feature = "socks5")]
+    #[clap(long, requires = "socks5", requires = "socks5_password")]
+    /// Username for the SOCKS5 proxy
+    socks5_user: Option<String>,
+
+    #[cfg(feature = "socks5")]
+    #[clap(long, requires = "socks5_user")]
+    /// Password for the SOCKS5 proxy
+    socks5_password: Option<String>,
+
+    #[clap(long, requires = "wol_interface", conflicts_with = "wake_chain")]
+    /// Send the magic packet as a raw Ethernet frame (EtherType 0x0842)
+    /// instead of UDP. Linux only, needs CAP_NET_RAW. Same as
+    /// `--wake-chain layer2`.
+    wol_layer2: bool,
+
+    #[clap(
+        long,
+        value_name = "ADDR:PORT",
+        requires = "relay_key_file",
+        conflicts_with = "wol_layer2"
+    )]
+    /// Have the `wol relay` at this address send the magic packet on its
+    /// own network, for a target on another subnet. Same as
+    /// `--wake-chain relay`.
+    relay_to: Option<String>,
+
+    #[clap(long, value_name = "FILE", requires = "relay_to")]
+    /// File containing the key shared with the --relay-to relay
+    relay_key_file: Option<String>,
+
+    #[clap(long, value_name = "PORT", default_value_t = 9, requires = "relay_to")]
+    /// Port the relay should send the magic packet to
+    relay_wol_port: u16,
+
+    #[clap(long, requires = "relay_to")]
+    /// Have the relay wait until the target answers pings before replying
+    relay_confirm: bool,
+
+    #[clap(long)]
+    /// Network interface to send magic packets out of (SO_BINDTODEVICE for
+    /// UDP, and the interface raw Ethernet frames are sent from). May be
+    /// repeated to send one packet out of each. Linux only.
+    wol_interface: Vec<String>,
+
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..=255))]
+    /// IP TTL of UDP magic packets, for reaching the server across routers
+    wol_ttl: Option<u32>,
+
+    #[clap(long)]
+    /// Source MAC address for raw Ethernet magic packets (defaults to the
+    /// address of each --wol-interface)
+    src_mac: Option<String>,
+
+    #[clap(long, requires = "wol_interface")]
+    /// Broadcast an ARP probe for the target out of --wol-interface before
+    /// each magic packet, so switches that have forgotten which port the
+    /// target is on relearn it. Linux only, needs CAP_NET_RAW.
+    arp_refresh: bool,
+
+    #[clap(long, value_delimiter = ',')]
+    /// Wake methods to try in order (e.g. "wol,esp32,ha"). Each one gets
+    /// --method-timeout to wake the server before the next is tried.
+    wake_chain: Vec<WakeMethod>,
+
+    #[clap(long, value_name = "URL")]
+    /// URL the esp32 wake method fetches, e.g. an ESP32 wired to the
+    /// server's power button
+    esp32_url: Option<String>,
+
+    #[clap(long, value_name = "URL")]
+    /// Home Assistant webhook the ha wake method posts the MAC and target
+    /// address to
+    ha_webhook: Option<String>,
+
+    #[clap(long, value_parser = parse_duration)]
+    /// How long to wait for each method in --wake-chain before moving on
+    /// (defaults to --timeout)
+    method_timeout: Option<Duration>,
+
+    #[cfg(feature = "tls")]
+    #[clap(long, requires = "tls_key")]
+    /// Terminate TLS from clients using this PEM certificate chain and
+    /// forward plaintext to the server. The handshake completes before
+    /// the server is woken, so clients that fail it never cause a wake.
+    tls_cert: Option<PathBuf>,
+
+    #[cfg(feature = "tls")]
+    #[clap(long, requires = "tls_cert")]
+    /// PEM private key for --tls-cert
+    tls_key: Option<PathBuf>,
+
+    #[cfg(feature = "tls")]
+    #[clap(long, requires = "tls_cert")]
+    /// Only accept clients presenting a certificate signed by a CA in this
+    /// PEM file. Other clients are rejected before anything is woken.
+    client_ca: Option<PathBuf>,
+
+    #[clap(long, value_name = "INTERVAL", value_parser = parse_interval)]
+    /// While connections are open, check the server is still up every
+    /// INTERVAL and re-send the magic packet if it stopped responding
+    keepalive_wake: Option<Duration>,
+
+    #[clap(long, default_value = "0", value_parser = parse_duration)]
+    /// Check whether the server is up every INTERVAL, even with no
+    /// connections, so new connections can skip the initial check (0 to
+    /// disable)
+    monitor_interval: Duration,
+
+    #[clap(long, value_name = "CRON", value_parser = parse_schedule)]
+    /// Also wake the server at these times, whether or not anyone connects
+    /// (cron expression with a seconds field, in local time, e.g.
+    /// "0 30 7 * * Mon-Fri")
+    warm_schedule: Option<Schedule>,
+
+    #[clap(long, value_name = "HH:MM", value_parser = parse_daily_time)]
+    /// Also send the magic packet every day at this local time if the
+    /// server is asleep (may be repeated). A simpler --warm-schedule.
+    wake_schedule: Vec<Schedule>,
+
+    #[clap(long, requires = "wake_schedule")]
+    /// Wait for the server to come up after a --wake-schedule packet, and
+    /// log whether it did
+    schedule_wait: bool,
+
+    #[clap(long, value_enum)]
+    /// If the server is up at startup, check --mac against its entry in the
+    /// ARP table
+    verify_mac: Option<VerifyMac>,
+
+    #[clap(long)]
+    /// Drop the connection when, after a wake, the server's ARP entry shows
+    /// a different MAC than --mac (normally just a warning)
+    strict_mac: bool,
+
+    #[clap(long)]
+    /// Refuse to start if --mac is multicast, broadcast or all zeroes,
+    /// which usually means a typo
+    require_unicast_mac: bool,
+
+    #[clap(long, value_enum, default_value_t = WakeDetect::Icmp)]
+    /// How to check whether the server is up (ignored with --socks5, which
+    /// always tries a connection through the proxy)
+    wake_detect: WakeDetect,
+
+    #[clap(long)]
+    /// Never wake the server; just proxy connections to it
+    no_wake: bool,
+
+    #[clap(long, value_name = "TEXT")]
+    /// Line of text to send to each client as soon as it connects, before
+    /// the server is woken. Only safe for protocols that tolerate leading
+    /// lines (SSH allows them before its version string); it corrupts
+    /// binary protocols.
+    connect_banner: Option<String>,
+
+    #[clap(long, value_name = "TOKEN")]
+    /// Require each connection to start with the 32 byte HMAC-SHA256 under
+    /// TOKEN of the current unix minute (big-endian u64 of time / 60),
+    /// closing it if that doesn't arrive within --handshake-timeout
+    auth_token: Option<String>,
+
+    #[clap(long, default_value = "5", value_parser = parse_duration)]
+    /// How long a client has to finish the TLS handshake and send its
+    /// --auth-token before it is dropped
+    handshake_timeout: Duration,
+
+    #[clap(long, value_name = "PORT", requires = "control_secret")]
+    /// Listen for signed UDP control packets (WAKE, STATUS, RESET STATS,
+    /// MAINT ON, MAINT REFUSE, MAINT OFF) on this port, on the --bind
+    /// address
+    control_port: Option<u16>,
+
+    #[clap(long, value_name = "SECRET", requires = "control_port")]
+    /// Shared secret control packets are signed with: each is the text
+    /// "TIMESTAMP COMMAND TAG", TAG being the hex HMAC-SHA256 of
+    /// "TIMESTAMP COMMAND"
+    control_secret: Option<String>,
+
+    #[clap(long, default_value = "30", value_parser = parse_duration, requires = "control_port")]
+    /// How far a control packet's timestamp may be from the local clock
+    control_replay_window: Duration,
+
+    #[clap(long)]
+    /// Before proxying, send the client "waking... Ns" lines while the
+    /// server wakes up, then "ready" (or "failed"). Only for clients that
+    /// expect this.
+    progress_protocol: bool,
+
+    #[clap(long)]
+    /// Close connections straight away if the server is asleep instead of
+    /// waking it and waiting
+    no_wait: bool,
+
+    #[clap(long, requires = "no_wait")]
+    /// With --no-wait, still send the magic packet (without waiting for the
+    /// server) so it is up for the next connection
+    no_wait_still_wake: bool,
+
+    #[clap(long)]
+    /// If the connection to the server fails or is closed by the server
+    /// mid-session, reconnect and keep proxying the client. Data in flight
+    /// is lost, so only use this for protocols that tolerate it.
+    reconnect_backend: bool,
+
+    #[clap(long, default_value = "0", value_parser = parse_linger)]
+    /// When a session ends, wait up to this long for the last data to reach
+    /// the server before closing (SO_LINGER on the server connection, in
+    /// whole seconds; 0 turns it off)
+    linger: Duration,
+
+    #[clap(long)]
+    /// If the connection to the server fails soon after it was made (see
+    /// --reconnect-threshold), as when the server crashes and reboots, wake
+    /// it again and reconnect without dropping the client
+    auto_reconnect: bool,
+
+    #[clap(long)]
+    /// While the server is asleep, close connections that send nothing
+    /// within --absorb-window instead of waking it, so uptime monitors that
+    /// just connect and hang up don't keep it awake. Not for protocols
+    /// where the server speaks first.
+    absorb_empty_connections: bool,
+
+    #[clap(long, default_value = "2", value_parser = parse_duration, requires = "absorb_empty_connections")]
+    /// How long a client has to send something with
+    /// --absorb-empty-connections
+    absorb_window: Duration,
+
+    #[clap(long, default_value = "10", value_parser = parse_duration, requires = "auto_reconnect")]
+    /// How long into a session a failing server connection still triggers
+    /// --auto-reconnect
+    reconnect_threshold: Duration,
+
+    #[clap(long)]
+    /// If the server answers but its service refuses connections (after
+    /// --connect-retry), send the whole wake sequence once more before
+    /// giving up
+    rewake_on_connect_fail: bool,
+
+    #[clap(long)]
+    /// JSON file to keep recent wake times in, so the wake ETA survives
+    /// restarts
+    state_file: Option<PathBuf>,
+
+    #[clap(long, value_name = "FILE")]
+    /// Keep track of when connections arrive, and on exit write a report
+    /// to this file suggesting quiet hours and a timeout
+    learn: Option<PathBuf>,
+
+    #[clap(long, value_name = "MULTIPLIER", default_value_t = 2.0, value_parser = parse_multiplier)]
+    /// Warn when a wake takes more than this many times the 90th percentile
+    /// of recent wakes, which can point to BIOS or NIC settings drifting
+    wake_anomaly_threshold: f64,
+
+    #[clap(long, value_name = "URL")]
+    /// Also POST a JSON alert to this http:// URL on a slow wake
+    wake_anomaly_webhook: Option<String>,
+
+    #[clap(long)]
+    /// Append a JSON line for each connection event (accepted, wake sent,
+    /// wake confirmed or failed, closed) to this file
+    event_log: Option<PathBuf>,
+
+    #[clap(long, value_name = "ADDR")]
+    /// Serve the same events live to WebSocket clients connecting to ADDR
+    events_bind: Option<SocketAddr>,
+
+    #[cfg(all(feature = "tui", unix))]
+    #[clap(long)]
+    /// Show a live dashboard of the open connections, the target's state
+    /// and the log in the terminal
+    tui: bool,
+
+    #[clap(long)]
+    /// Write the process ID to this file, removing it again on shutdown
+    pid_file: Option<PathBuf>,
+
+    #[clap(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    /// Run on N worker threads rather than one, so large transfers and
+    /// wake detection for new connections can run in parallel
+    worker_threads: Option<u32>,
+
+    #[clap(long)]
+    /// Replace the last octet of IPv4 client addresses, and the last 64
+    /// bits of IPv6 ones, with "x" in log output and events
+    mask_client_ips: bool,
+
+    #[clap(short, long)]
+    /// Don't show a progress line while waiting for the server to wake up
+    quiet: bool,
+
+    #[clap(long)]
+    /// Log routine lines for each connection (proxying, closed) at debug
+    /// level instead of info, for targets that are connected to constantly
+    /// such as by health checks. Wakes and errors are still logged.
+    no_log_connections: bool,
+
+    #[clap(long)]
+    /// Accept connections and log what would be done, without sending
+    /// magic packets or forwarding any data
+    dry_run: bool,
+}
+
+/// Ways of waking the server.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
+enum WakeMethod {
+    /// UDP magic packet to the target address
+    Wol,
+    /// Raw Ethernet frame out of --wol-interface
+    Layer2,
+    /// Signed request to the --relay-to relay
+    Relay,
+    /// HTTP GET of --esp32-url
+    Esp32,
+    /// HTTP POST to the Home Assistant --ha-webhook
+    Ha,
+}
+
+impl std::fmt::Display for WakeMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WakeMethod::Wol => "wol",
+            WakeMethod::Layer2 => "layer2",
+            WakeMethod::Relay => "relay",
+            WakeMethod::Esp32 => "esp32",
+            WakeMethod::Ha => "ha",
+        })
+    }
+}
+
+impl std::fmt::Display for WakeDetect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WakeDetect::Icmp => "icmp",
+            WakeDetect::Tcp => "tcp",
+            WakeDetect::Http => "http",
+        })
+    }
+}
+
+fn parse_name(s: &str) -> Result<String, String> {
+    if s.is_empty() || !s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err("names may only contain letters, digits and hyphens".to_string());
+    }
+    Ok(s.to_string())
+}
+
+fn parse_multiplier(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>().map_err(|e| e.to_string())? {
+        m if m.is_finite() && m > 0.0 => Ok(m),
+        _ => Err("must be a positive number".to_string()),
+    }
+}
+
+/// A --linger duration. SO_LINGER only counts whole seconds, and a
+/// fraction would be cut off, down to an abortive close for anything under
+/// a second.
+fn parse_linger(s: &str) -> Result<Duration, String> {
+    match parse_duration(s)? {
+        d if d.subsec_nanos() != 0 => Err("must be a whole number of seconds".to_string()),
+        d => Ok(d),
+    }
+}
+
+/// The most that fits in one unfragmented echo request on a 1500 byte MTU.
+const MAX_PING_PAYLOAD: usize = 1472;
+
+/// The same for IPv6, whose header is 20 bytes longer.
+const MAX_PING_PAYLOAD_V6: usize = 1452;
+
+/// Bytes for --ping-payload. A newtype so clap doesn't take `Vec<u8>` for
+/// a list of values.
+#[derive(Clone)]
+struct PingPayload(Vec<u8>);
+
+fn parse_ping_payload(s: &str) -> Result<PingPayload, String> {
+    let payload = hex::decode(s).map_err(|e| format!("invalid hex: {e}"))?;
+    if payload.len() > MAX_PING_PAYLOAD {
+        return Err(format!(
+            "{} bytes is too long, the most is {}",
+            payload.len(),
+            MAX_PING_PAYLOAD
+        ));
+    }
+    Ok(PingPayload(payload))
+}
+
+/// Check that `payload` fits in one unfragmented echo request to each of
+/// `targets`, which the parser can't know the address family of.
+fn check_ping_payload(payload: &[u8], targets: &[SocketAddr]) -> Result<()> {
+    if payload.len() > MAX_PING_PAYLOAD_V6 && targets.iter().any(SocketAddr::is_ipv6) {
+        bail!(
+            "--ping-payload is {} bytes, but the most for an IPv6 target is {}",
+            payload.len(),
+            MAX_PING_PAYLOAD_V6
+        );
+    }
+    Ok(())
+}
+
+fn parse_regex(s: &str) -> Result<Regex, String> {
+    Regex::new(s).map_err(|e| e.to_string())
+}
+
+fn parse_schedule(s: &str) -> Result<Schedule, String> {
+    Schedule::from_str(s).map_err(|e| format!("invalid cron expression: {e}"))
+}
+
+/// Parse a time of day into a schedule firing at it every day.
+fn parse_daily_time(s: &str) -> Result<Schedule, String> {
+    let time = NaiveTime::parse_from_str(s, "%H:%M").map_err(|e| format!("invalid time: {e}"))?;
+    parse_schedule(&format!("0 {} {} * * *", time.minute(), time.hour()))
+}
+
+/// A daily window of local time, which may run past midnight.
+#[derive(Clone, Copy)]
+struct HourWindow {
+    start: NaiveTime,
+    end: NaiveTime,
 }
 
-/// Wait for the target to come online, timing out after the given
-/// timeout.
-async fn ping(target: &IpAddr, timeout: Duration) -> bool {
-    let ping_opts = PingOptions {
-        ttl: 128,
-        dont_fragment: true,
+impl HourWindow {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+fn parse_hours(s: &str) -> Result<HourWindow, String> {
+    let (start, end) = s
+        .split_once('-')
+        .ok_or_else(|| "expected HH:MM-HH:MM".to_string())?;
+    let parse = |t: &str| {
+        NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|e| format!("invalid time {t:?}: {e}"))
     };
-    let start = std::time::Instant::now();
-    loop {
-        if start.elapsed() > timeout {
-            return false;
+    Ok(HourWindow {
+        start: parse(start)?,
+        end: parse(end)?,
+    })
+}
+
+/// What to do when --mac doesn't match the target's ARP entry.
+#[derive(Clone, Copy, ValueEnum)]
+enum VerifyMac {
+    /// Log a warning and carry on
+    Warn,
+    /// Refuse to start
+    Fail,
+}
+
+/// Ways of checking whether the server is up.
+#[derive(Clone, Copy, ValueEnum)]
+enum WakeDetect {
+    /// ICMP echo request to the target host
+    Icmp,
+    /// TCP connection to the target port
+    Tcp,
+    /// HTTP request to the target port, expecting any HTTP response
+    Http,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Listen for signed wake requests and send the magic packet on the
+    /// local network
+    #[command(long_flag = "relay-mode")]
+    Relay(RelayArgs),
+
+    /// Send a magic packet and exit
+    SendWol(SendWolArgs),
+
+    /// Check once whether the target is up, exiting with 1 if it isn't
+    CheckTarget(CheckTargetArgs),
+
+    /// Validate the proxy arguments and print the configured target
+    ListTargets(Box<ListTargetsArgs>),
+
+    /// Print a shell completion script
+    GenerateCompletions(CompletionsArgs),
+
+    /// Print the man page
+    GenerateMan(ManArgs),
+}
+
+#[derive(clap::Args)]
+struct RelayArgs {
+    #[clap(short, long, visible_alias = "relay-listen", value_name = "ADDR:PORT")]
+    /// The address to listen for wake requests on
+    bind: String,
+
+    #[clap(short, long)]
+    /// File containing the shared key used to sign wake requests
+    key_file: String,
+
+    #[clap(long, default_value = "255.255.255.255:9")]
+    /// Where to send the magic packet (usually the LAN broadcast address)
+    broadcast: String,
+
+    #[clap(long, default_value = "30", value_parser = parse_duration)]
+    /// Maximum allowed difference between the request timestamp and the
+    /// local clock
+    max_skew: Duration,
+
+    #[clap(long, default_value = "120", value_parser = parse_duration)]
+    /// How long to wait for the target to answer pings when a request asks
+    /// for confirmation
+    confirm_timeout: Duration,
+
+    #[command(flatten)]
+    ping: PingArgs,
+
+    #[command(flatten)]
+    privileges: PrivilegeArgs,
+
+    #[command(flatten)]
+    log: LogArgs,
+}
+
+#[derive(clap::Args)]
+struct SendWolArgs {
+    #[clap(short, long)]
+    /// MAC address of the machine to wake
+    mac: String,
+
+    #[clap(short, long, default_value = "255.255.255.255")]
+    /// Address to send the magic packet to (usually the LAN broadcast
+    /// address)
+    dest: IpAddr,
+
+    #[clap(short, long, default_value = "9")]
+    /// UDP port to send the magic packet to
+    port: u16,
+
+    #[clap(short, long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..))]
+    /// Number of magic packets to send
+    repeat: u32,
+}
+
+#[derive(clap::Args)]
+struct ListTargetsArgs {
+    #[clap(long)]
+    /// Check whether each target is up
+    probe: bool,
+
+    #[command(flatten)]
+    proxy: ProxyArgs,
+
+    #[command(flatten)]
+    ping: PingArgs,
+}
+
+/// Print a table of the configured targets, exiting with 1 if any of them
+/// is invalid.
+async fn list_targets(args: ListTargetsArgs) -> Result<()> {
+    let proxy = &args.proxy;
+    let mac = parse_mac(&proxy.mac).and_then(|mac| {
+        if proxy.require_unicast_mac {
+            check_unicast(&mac)?;
+        }
+        Ok(mac)
+    });
+    let target = resolve_configured(proxy).await;
+    let state = match (&mac, &target) {
+        (Err(e), _) | (_, Err(e)) => format!("invalid: {}", e),
+        (Ok(_), Ok(addrs)) if args.probe => {
+            let start = Instant::now();
+            let ping = ping_settings(&args.ping);
+            if detect(proxy.wake_detect, addrs, &ping).await {
+                format!("online ({} ms)", start.elapsed().as_millis())
+            } else {
+                "offline".to_string()
+            }
         }
-        match ping_rs::send_ping_async(
+        (Ok(_), Ok(_)) => "unknown".to_string(),
+    };
+    let strategy = match proxy.uses_socks5() {
+        true => "socks5".to_string(),
+        false => proxy.wake_detect.to_string(),
+    };
+    let rows = [
+        [
+            "Name", "Bind", "Target", "MAC", "Strategy", "Timeout", "State",
+        ]
+        .map(String::from),
+        [
+            proxy.name.clone().unwrap_or_else(|| "-".to_string()),
+            proxy.bind.clone(),
+            proxy
+                .target
+                .clone()
+                .or(proxy.target_srv.clone())
+                .unwrap_or_default(),
+            mac.as_ref().map_or_else(|_| proxy.mac.clone(), format_mac),
+            strategy,
+            humantime::format_duration(proxy.timeout).to_string(),
+            state,
+        ],
+    ];
+    let widths: Vec<usize> = (0..rows[0].len())
+        .map(|i| rows.iter().map(|row| row[i].len()).max().unwrap_or(0))
+        .collect();
+    for row in &rows {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", cells.join(" | ").trim_end());
+    }
+
+    if mac.is_err() || target.is_err() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CheckTargetArgs {
+    #[clap(short, long)]
+    /// Address of the target
+    target: SocketAddr,
+
+    #[clap(long, value_enum, default_value_t = WakeDetect::Icmp)]
+    /// How to check whether the target is up
+    wake_detect: WakeDetect,
+
+    #[command(flatten)]
+    ping: PingArgs,
+}
+
+/// Run one round of wake detection against the target and report the
+/// result.
+async fn check_target(args: CheckTargetArgs) -> Result<()> {
+    check_ping_payload(&args.ping.ping_payload.0, &[args.target])?;
+    let start = Instant::now();
+    let ping = ping_settings(&args.ping);
+    if !detect(args.wake_detect, &[args.target], &ping).await {
+        println!("{} is offline", args.target);
+        std::process::exit(1);
+    }
+    println!(
+        "{} is online ({} ms)",
+        args.target,
+        start.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+/// Send the magic packet(s) described by `args` without starting a
+/// listener.
+fn send_wol(args: SendWolArgs) -> Result<()> {
+    let mac = parse_mac(&args.mac)?;
+    let dest = SocketAddr::new(args.dest, args.port);
+    for _ in 0..args.repeat {
+        send_magic_packet(&mac, &dest, None, None)?;
+    }
+    println!(
+        "Sent {} magic packet(s) for {} to {}",
+        args.repeat,
+        format_mac(&mac),
+        dest
+    );
+    Ok(())
+}
+
+// no doc comment, as clap would take it for the help of every command
+// this is flattened into
+#[derive(clap::Args)]
+struct PingArgs {
+    #[clap(long, default_value_t = 128, value_parser = clap::value_parser!(u8).range(1..))]
+    /// TTL of the ICMP echo requests used to check whether the server is up
+    ping_ttl: u8,
+
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    /// Set the don't-fragment bit on the ICMP echo requests (some VPNs drop
+    /// them otherwise)
+    ping_df: bool,
+
+    #[clap(long, value_name = "HEX", default_value = "deadbeef", value_parser = parse_ping_payload)]
+    /// Payload of the ICMP echo requests, as hex (up to 1472 bytes, or 1452
+    /// for IPv6 targets). Some firewalls drop echo requests with no
+    /// payload, or with one that doesn't look like ping(1)'s
+    ping_payload: PingPayload,
+}
+
+/// How to build the ICMP echo requests sent to the target.
+struct PingSettings {
+    options: PingOptions,
+    payload: Vec<u8>,
+}
+
+/// The settings pings go out with, from --ping-ttl, --ping-df and
+/// --ping-payload.
+fn ping_settings(args: &PingArgs) -> PingSettings {
+    PingSettings {
+        options: PingOptions {
+            ttl: args.ping_ttl,
+            dont_fragment: args.ping_df,
+        },
+        payload: args.ping_payload.0.clone(),
+    }
+}
+
+/// Whether a wake that took `latency` took more than `threshold` times the
+/// usual `p90`. Nothing is slower than a limit too long for a `Duration`.
+fn is_slow_wake(latency: Duration, p90: Duration, threshold: f64) -> bool {
+    Duration::try_from_secs_f64(p90.as_secs_f64() * threshold).is_ok_and(|limit| latency > limit)
+}
+
+/// Ping all of the target's addresses at once and report whether any of
+/// them answered. Dual-stack hosts that are still booting sometimes only
+/// answer on one address family.
+async fn ping(targets: &[IpAddr], settings: &PingSettings) -> bool {
+    let pings = targets.iter().map(|target| {
+        Box::pin(ping_rs::send_ping_async(
             target,
             Duration::from_secs(1),
-            Arc::new(&[0u8; 0]),
-            Some(&ping_opts),
-        )
-        .await
+            Arc::new(settings.payload.as_slice()),
+            Some(&settings.options),
+        ))
+    });
+    any_succeeds(pings).await
+}
+
+/// Run all of `probes` at once, and report whether any of them succeeds
+/// as soon as one does.
+async fn any_succeeds<T, E>(
+    probes: impl IntoIterator<Item = impl std::future::Future<Output = Result<T, E>> + Unpin>,
+) -> bool {
+    let probes: Vec<_> = probes.into_iter().collect();
+    // select_ok panics when given nothing to wait for
+    !probes.is_empty() && select_ok(probes).await.is_ok()
+}
+
+/// Make sure ICMP pings can be sent at all. Without the privileges for it
+/// every ping fails, so the proxy would never see the server come up.
+async fn check_ping_permission() -> Result<()> {
+    let loopback = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let result =
+        ping_rs::send_ping_async(&loopback, Duration::from_secs(1), Arc::new(&[0u8; 0]), None)
+            .await;
+    match result {
+        Err(e) => ping_permission(&e),
+        Ok(_) => Ok(()),
+    }
+}
+
+/// The error for a ping that failed because pings aren't allowed at all.
+/// Other failures are left for the pings to the server to run into.
+fn ping_permission(error: &PingError) -> Result<()> {
+    match error {
+        PingError::OsError(code, message)
+            if *code == libc::EACCES as u32 || *code == libc::EPERM as u32 =>
         {
-            Ok(_) => return true,
-            Err(_) => (),
-        };
+            bail!(
+                "not allowed to send pings ({}), so --wake-detect icmp can't work. \
+                 Grant CAP_NET_RAW, allow unprivileged pings with the net.ipv4.ping_group_range \
+                 sysctl, or use --wake-detect tcp",
+                message
+            )
+        }
+        _ => Ok(()),
     }
 }
 
-async fn handle_client(
-    mut stream: TcpStream,
-    target_addr: &SocketAddr,
+/// Check whether the target is up using the given method, giving up after
+/// a second. `targets` holds every address the target resolved to, first
+/// the one connections go to; pings go to all of them.
+async fn detect(method: WakeDetect, targets: &[SocketAddr], settings: &PingSettings) -> bool {
+    let timeout = Duration::from_secs(1);
+    let Some(target) = targets.first() else {
+        return false;
+    };
+    match method {
+        WakeDetect::Icmp => {
+            let ips: Vec<IpAddr> = targets.iter().map(SocketAddr::ip).collect();
+            ping(&ips, settings).await
+        }
+        WakeDetect::Tcp => matches!(
+            tokio::time::timeout(timeout, TcpStream::connect(target)).await,
+            Ok(Ok(_))
+        ),
+        WakeDetect::Http => matches!(
+            tokio::time::timeout(timeout, http_probe(target)).await,
+            Ok(Ok(true))
+        ),
+    }
+}
+
+/// POST `body` to a plain http:// URL, failing unless the reply is a 2xx.
+async fn post_json(url: &str, body: &serde_json::Value) -> Result<()> {
+    http_request("POST", url, Some(body)).await
+}
+
+/// Make a `method` request to a plain http:// URL, with `body` as JSON if
+/// there is one, failing unless the reply is a 2xx.
+async fn http_request(method: &str, url: &str, body: Option<&serde_json::Value>) -> Result<()> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        bail!("only http:// URLs are supported: {}", url);
+    };
+    let (host, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let addr = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    let mut stream = TcpStream::connect(addr).await?;
+    let mut head = format!("{} {} HTTP/1.0\r\nHost: {}\r\n", method, path, host);
+    let body = body.map(serde_json::to_vec).transpose()?;
+    if let Some(body) = &body {
+        head += &format!(
+            "Content-Type: application/json\r\nContent-Length: {}\r\n",
+            body.len()
+        );
+    }
+    head += "\r\n";
+    stream.write_all(head.as_bytes()).await?;
+    if let Some(body) = &body {
+        stream.write_all(body).await?;
+    }
+    // "HTTP/1.x NNN"
+    let mut status = [0u8; 12];
+    stream.read_exact(&mut status).await?;
+    if !status.starts_with(b"HTTP/") || status[9] != b'2' {
+        bail!(
+            "{} answered {}",
+            url,
+            String::from_utf8_lossy(&status[..]).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Send a `HEAD /` request and check the reply looks like HTTP.
+async fn http_probe(target: &SocketAddr) -> Result<bool> {
+    let mut stream = TcpStream::connect(target).await?;
+    let request = format!("HEAD / HTTP/1.0\r\nHost: {}\r\n\r\n", target.ip());
+    stream.write_all(request.as_bytes()).await?;
+    let mut status = [0u8; 5];
+    stream.read_exact(&mut status).await?;
+    Ok(&status == b"HTTP/")
+}
+
+/// Send a magic packet for `mac` to `dest`, optionally forcing it out of
+/// `interface`.
+fn send_magic_packet(
     mac: &[u8; 6],
-    timeout: u64,
+    dest: &SocketAddr,
+    interface: Option<&str>,
+    ttl: Option<u32>,
 ) -> Result<()> {
-    // Check if the server is already online, and skip WOL if it is:
-    if !ping(&target_addr.ip(), Duration::from_secs(1)).await {
-        // Send the wake-on-lan packet to the server
-        let pkt = wake_on_lan::MagicPacket::new(mac);
-        let sa_any = SocketAddr::from_str("[::]:0").unwrap();
-        println!("Sending magic packet...");
-        pkt.send_to(target_addr, &sa_any.try_into()?)?;
-
-        // Wait for the server to wake up
-        println!("Waiting for server to wake up...");
-        if !ping(&target_addr.ip(), Duration::from_secs(timeout)).await {
-            bail!("Server did not wake up in time");
-        }
+    let pkt = wake_on_lan::MagicPacket::new(mac);
+    if interface.is_none() && ttl.is_none() {
+        let from = match dest {
+            SocketAddr::V4(_) => SocketAddr::from_str("0.0.0.0:0")?,
+            SocketAddr::V6(_) => SocketAddr::from_str("[::]:0")?,
+        };
+        pkt.send_to(dest, &from)?;
+        return Ok(());
     }
 
-    // Proxy the connection to the server
-    println!("Proxying connection to server...");
-    let mut server_conn = TcpStream::connect(target_addr).await?;
-    tokio::io::copy_bidirectional(&mut server_conn, &mut stream).await?;
+    let socket = Socket::new(Domain::for_address(*dest), Type::DGRAM, Some(Protocol::UDP))?;
+    if let Some(interface) = interface {
+        bind_device(&socket, interface)?;
+    }
+    match (ttl, dest) {
+        (Some(ttl), SocketAddr::V4(_)) => socket.set_ttl(ttl)?,
+        (Some(hops), SocketAddr::V6(_)) => socket.set_unicast_hops_v6(hops)?,
+        (None, _) => {}
+    }
+    socket.set_broadcast(true)?;
+    socket.send_to(pkt.magic_bytes(), &(*dest).into())?;
+    Ok(())
+}
 
-    // Done!
+/// Restrict `socket` to sending out of `interface` (SO_BINDTODEVICE).
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, interface: &str) -> Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))?;
     Ok(())
 }
 
-/// Parse a MAC address into a [u8; 6]
-fn parse_mac(mac: &str) -> Result<[u8; 6]> {
-    let mut out = [0u8; 6];
-    for i in 0..6 {
-        out[i] = u8::from_str_radix(&mac[3 * i..(3 * i) + 2], 16)?;
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, _interface: &str) -> Result<()> {
+    bail!("--wol-interface is only supported on Linux");
+}
+
+/// Set SO_PRIORITY on a TCP connection.
+#[cfg(target_os = "linux")]
+fn set_priority(stream: &TcpStream, priority: u32) -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // socket2 0.5 has no setter for this one
+    let priority = priority as libc::c_int;
+    // SAFETY: the fd is a live socket for the duration of the call, and
+    // the option value is a c_int as SO_PRIORITY expects
+    let ret = unsafe {
+        libc::setsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PRIORITY,
+            &priority as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error().into());
     }
-    Ok(out)
+    Ok(())
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
-    let args = Args::parse();
+#[cfg(not(target_os = "linux"))]
+fn set_priority(_stream: &TcpStream, _priority: u32) -> Result<()> {
+    bail!("--so-priority is only supported on Linux");
+}
 
-    // parse mac address:
-    let mac = parse_mac(&args.mac)?;
+impl ProxyArgs {
+    /// Whether the server is reached through --socks5.
+    fn uses_socks5(&self) -> bool {
+        #[cfg(feature = "socks5")]
+        return self.socks5.is_some();
+        #[cfg(not(feature = "socks5"))]
+        false
+    }
+}
 
-    // split target address into ip/port:
-    let target_addr = SocketAddrV4::from_str(&args.target)?;
+/// A SOCKS5 proxy the server is reached through.
+#[cfg(feature = "socks5")]
+struct Socks5 {
+    addr: String,
+    /// username and password
+    auth: Option<(String, String)>,
+}
 
-    let listener = TcpListener::bind(&args.bind).await?;
+#[cfg(feature = "socks5")]
+impl Socks5 {
+    async fn connect(&self, target: &SocketAddr) -> Result<TcpStream> {
+        let stream = match &self.auth {
+            Some((user, password)) => {
+                Socks5Stream::connect_with_password(self.addr.as_str(), *target, user, password)
+                    .await?
+            }
+            None => Socks5Stream::connect(self.addr.as_str(), *target).await?,
+        };
+        Ok(stream.into_inner())
+    }
+}
+
+/// Sends wake requests to a `wol relay` on another network.
+struct RelayClient {
+    addr: String,
+    key: Vec<u8>,
+    wol_port: u16,
+    confirm: bool,
+}
+
+impl RelayClient {
+    /// Ask the relay to wake `mac`, giving up after `timeout`.
+    async fn wake(&self, mac: &[u8; 6], target: IpAddr, timeout: Duration) -> Result<()> {
+        let req = WakeRequest {
+            mac: format_mac(mac),
+            timestamp: relay::unix_time(),
+            nonce: relay::nonce(),
+            port: Some(self.wol_port),
+            target: Some(target),
+            confirm: self.confirm,
+        };
+        let frame = relay::sign(&self.key, &req)?;
+        let exchange = async {
+            let mut stream = TcpStream::connect(self.addr.as_str()).await?;
+            stream.write_all(&frame).await?;
+            relay::read_response(&mut stream).await
+        };
+        let resp = match tokio::time::timeout(timeout, exchange).await {
+            Ok(resp) => resp?,
+            Err(_) => bail!("relay {} did not answer in time", self.addr),
+        };
+        if !resp.success {
+            bail!(
+                "relay {} failed: {}",
+                self.addr,
+                resp.error.as_deref().unwrap_or("no reason given")
+            );
+        }
+        if let Some(latency) = resp.latency_ms {
+            info!(
+                "Relay {} saw {} answer after {} ms",
+                self.addr, target, latency
+            );
+        }
+        Ok(())
+    }
+}
+
+/// How many recent source ports --reuse-port-avoidance steers clear of.
+const RECENT_PORTS: usize = 64;
+
+/// Connect to `addr` from a source port that isn't in `recent`, and add
+/// the port used to it.
+async fn connect_avoiding(addr: SocketAddr, recent: &Mutex<VecDeque<u16>>) -> Result<TcpStream> {
+    // rejected sockets are kept bound until the end, so the kernel can't
+    // hand out the same port again
+    let mut rejected = Vec::new();
+    let socket = loop {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        let unspecified = match addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        socket.bind(SocketAddr::new(unspecified, 0))?;
+        let port = socket.local_addr()?.port();
+        let mut recent = recent.lock().unwrap();
+        if !recent.contains(&port) || rejected.len() == RECENT_PORTS {
+            if recent.len() == RECENT_PORTS {
+                recent.pop_front();
+            }
+            recent.push_back(port);
+            break socket;
+        }
+        rejected.push(socket);
+    };
+    drop(rejected);
+    Ok(socket.connect(addr).await?)
+}
+
+/// Read a relay key file, which must not be empty.
+fn read_key(path: &str) -> Result<Vec<u8>> {
+    let key = std::fs::read_to_string(path)?;
+    let key = key.trim();
+    if key.is_empty() {
+        bail!("key file {} is empty", path);
+    }
+    Ok(key.as_bytes().to_vec())
+}
+
+/// How many past wake latencies are kept for the ETA.
+const WAKE_HISTORY_LEN: usize = 10;
+
+/// Contents of the --state-file.
+#[derive(Serialize, Deserialize)]
+struct State {
+    wake_latencies_ms: Vec<u64>,
+    /// Unset while the proxy is running, so a file left by a crash can be
+    /// told apart from one written on shutdown
+    clean_shutdown: bool,
+}
+
+/// Recent wake latencies, optionally persisted to a state file.
+struct WakeHistory {
+    latencies: VecDeque<Duration>,
+    path: Option<PathBuf>,
+    /// Bumped for every snapshot, so an older one never overwrites a newer
+    saves: u64,
+    /// The latest snapshot written to the state file
+    written: Arc<Mutex<u64>>,
+}
+
+/// The wake history as it was at some point, ready to be written to the
+/// state file off the async runtime.
+struct Snapshot {
+    path: Option<PathBuf>,
+    state: State,
+    generation: u64,
+    written: Arc<Mutex<u64>>,
+}
+
+impl Snapshot {
+    /// Write the state file, if there is one and nothing newer has been
+    /// written to it. It is written next to its final location and renamed
+    /// into place, so a crash never leaves it half-written.
+    fn save(self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut written = self.written.lock().unwrap();
+        if *written > self.generation {
+            return Ok(());
+        }
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&self.state)?)?;
+        std::fs::rename(&tmp, path)?;
+        *written = self.generation;
+        Ok(())
+    }
+
+    /// Save on a blocking thread, logging a failure.
+    fn save_in_background(self) {
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = self.save() {
+                warn!("failed to save wake history: {}", e);
+            }
+        });
+    }
+}
+
+impl WakeHistory {
+    fn load(path: Option<PathBuf>) -> Result<Self> {
+        let mut latencies = VecDeque::new();
+        if let Some(path) = path.as_ref().filter(|path| path.exists()) {
+            let state: State = serde_json::from_str(&std::fs::read_to_string(path)?)
+                .map_err(|e| anyhow!("invalid state file {}: {}", path.display(), e))?;
+            if !state.clean_shutdown {
+                info!(
+                    "{} was not saved on shutdown, the wake history may be stale",
+                    path.display()
+                );
+            }
+            latencies.extend(
+                state
+                    .wake_latencies_ms
+                    .into_iter()
+                    .map(Duration::from_millis),
+            );
+        }
+        while latencies.len() > WAKE_HISTORY_LEN {
+            latencies.pop_front();
+        }
+        Ok(Self {
+            latencies,
+            path,
+            saves: 0,
+            written: Arc::default(),
+        })
+    }
+
+    fn record(&mut self, latency: Duration) {
+        if self.latencies.len() == WAKE_HISTORY_LEN {
+            self.latencies.pop_front();
+        }
+        self.latencies.push_back(latency);
+    }
+
+    /// The current latencies, to be saved without holding the lock.
+    fn snapshot(&mut self, clean_shutdown: bool) -> Snapshot {
+        self.saves += 1;
+        Snapshot {
+            path: self.path.clone(),
+            state: State {
+                wake_latencies_ms: self
+                    .latencies
+                    .iter()
+                    .map(|latency| latency.as_millis() as u64)
+                    .collect(),
+                clean_shutdown,
+            },
+            generation: self.saves,
+            written: self.written.clone(),
+        }
+    }
+
+    /// 90th percentile wake latency, given enough samples.
+    fn p90(&self) -> Option<Duration> {
+        if self.latencies.len() < 3 {
+            return None;
+        }
+        let mut sorted: Vec<Duration> = self.latencies.iter().copied().collect();
+        sorted.sort();
+        Some(sorted[(sorted.len() * 9).div_ceil(10) - 1])
+    }
+
+    /// Summary of the recent wake latencies for STATUS.
+    fn stats(&self) -> serde_json::Value {
+        let mut sorted: Vec<u64> = self
+            .latencies
+            .iter()
+            .map(|latency| latency.as_millis() as u64)
+            .collect();
+        sorted.sort();
+        let count = sorted.len();
+        serde_json::json!({
+            "count": count,
+            "min_ms": sorted.first(),
+            "mean_ms": (count > 0).then(|| sorted.iter().sum::<u64>() / count as u64),
+            "max_ms": sorted.last(),
+            "p95_ms": (count > 0).then(|| sorted[(count * 19).div_ceil(20) - 1]),
+        })
+    }
+
+    /// Forget every recorded wake; the state file is left to the caller.
+    fn reset(&mut self) {
+        self.latencies.clear();
+    }
+
+    /// Average wake latency and the number of wakes it is based on, once
+    /// there are enough samples for it to mean something.
+    fn eta(&self) -> Option<(Duration, usize)> {
+        let samples = self.latencies.len();
+        if samples < 3 {
+            return None;
+        }
+        let total: Duration = self.latencies.iter().sum();
+        Some((total / samples as u32, samples))
+    }
+}
+
+/// Stops connections from piling up behind a server that keeps failing.
+///
+/// Closed: connections go ahead. Open: after `threshold` failures in a row,
+/// connections are refused until `reset` has passed. Half-open: then one
+/// connection goes ahead; its success closes the breaker again, its
+/// failure opens it for another `reset`.
+struct CircuitBreaker {
+    threshold: u32,
+    reset: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+    /// The connection let through as the half-open trial, while it is in
+    /// progress
+    probe: Option<u64>,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: u32, reset: Duration) -> Self {
+        Self {
+            threshold,
+            reset,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    /// Whether new connection `id` may go ahead.
+    fn allow(&self, id: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            None => true,
+            Some(until) if state.probe.is_some() || Instant::now() < until => false,
+            Some(_) => {
+                state.probe = Some(id);
+                true
+            }
+        }
+    }
+
+    /// Forget connection `id`, which says nothing about the server. If it
+    /// was the half-open trial, another one can be.
+    fn abandon(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.probe == Some(id) {
+            state.probe = None;
+        }
+    }
+
+    /// Record how connection `id` went, returning whether this opened the
+    /// breaker.
+    fn record(&self, id: u64, success: bool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if success {
+            *state = BreakerState::default();
+            return false;
+        }
+        state.failures += 1;
+        if state.probe == Some(id)
+            || (state.open_until.is_none() && state.failures >= self.threshold)
+        {
+            state.probe = None;
+            state.open_until = Some(Instant::now() + self.reset);
+            return true;
+        }
+        false
+    }
+}
+
+/// Token bucket shared by every magic packet the proxy sends, refilling
+/// at the configured rate per minute. Sends that find it empty queue up
+/// and go out in order as tokens come back.
+struct WolRateLimiter {
+    depth: Option<usize>,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: u32,
+    /// Sends waiting for a token, oldest first
+    queue: VecDeque<oneshot::Sender<()>>,
+}
+
+impl WolRateLimiter {
+    /// Create a full bucket and start refilling it.
+    fn start(per_minute: u32, depth: Option<usize>) -> Arc<Self> {
+        let limiter = Arc::new(Self {
+            depth,
+            bucket: Mutex::new(Bucket {
+                tokens: per_minute,
+                queue: VecDeque::new(),
+            }),
+        });
+        let refill = limiter.clone();
+        tokio::spawn(async move {
+            let period = Duration::from_secs(60) / per_minute;
+            let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                ticker.tick().await;
+                let mut bucket = refill.bucket.lock().unwrap();
+                // hand the token straight to the oldest waiting send; ones
+                // whose connection has gone away don't count
+                let mut handed_over = false;
+                while let Some(waiter) = bucket.queue.pop_front() {
+                    if waiter.send(()).is_ok() {
+                        handed_over = true;
+                        break;
+                    }
+                }
+                if !handed_over && bucket.tokens < per_minute {
+                    bucket.tokens += 1;
+                }
+            }
+        });
+        limiter
+    }
+
+    /// Wait for a token. Returns false if this send was dropped from a
+    /// full queue instead.
+    async fn acquire(&self) -> bool {
+        let rx = {
+            let mut bucket = self.bucket.lock().unwrap();
+            if bucket.queue.is_empty() && bucket.tokens > 0 {
+                bucket.tokens -= 1;
+                return true;
+            }
+            let (tx, rx) = oneshot::channel();
+            bucket.queue.push_back(tx);
+            if self.depth.is_some_and(|depth| bucket.queue.len() > depth) {
+                bucket.queue.pop_front();
+            }
+            rx
+        };
+        rx.await.is_ok()
+    }
+}
+
+/// Send a magic packet out of each of several interfaces. Failures on some
+/// of them are only logged; it's an error only if none worked.
+fn send_on_each<T>(
+    interfaces: &[T],
+    name: impl Fn(&T) -> &str,
+    send: impl Fn(&T) -> Result<()>,
+) -> Result<()> {
+    let mut sent = false;
+    let mut last_error = None;
+    for interface in interfaces {
+        match send(interface) {
+            Ok(()) => sent = true,
+            Err(e) => {
+                if interfaces.len() > 1 {
+                    warn!("failed to send magic packet on {}: {}", name(interface), e);
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+    match last_error {
+        Some(e) if !sent => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// What --confirm-banner expects from the service.
+struct BannerCheck {
+    pattern: Option<Regex>,
+    max_bytes: usize,
+    timeout: Duration,
+}
+
+/// Where raw Ethernet magic packets are sent from.
+struct Layer2 {
+    interface: String,
+    src_mac: [u8; 6],
+}
+
+/// A client that failed --auth-token or never finished its handshake.
+/// Kept apart from other errors so strangers can't trip the circuit
+/// breaker.
+#[derive(Debug)]
+struct Unauthenticated(String);
+
+impl std::fmt::Display for Unauthenticated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Unauthenticated {}
+
+/// A connection that ended without trying the server: turned away by
+/// --no-wait, maintenance mode or --max-wakes-per-hour, or given up on by
+/// the client while the server was waking. Like `Unauthenticated`, it says
+/// nothing about whether the server is working.
+#[derive(Debug)]
+struct Declined(String);
+
+impl std::fmt::Display for Declined {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Declined {}
+
+/// What became of a connection that didn't fail.
+#[derive(Debug, PartialEq)]
+enum Served {
+    /// Proxied to the server
+    Proxied,
+    /// Closed without waking the server, as the client sent nothing
+    Absorbed,
+}
+
+/// Whether a connection shows the server working, for the circuit
+/// breaker, or None if it never got as far as trying the server.
+fn outcome(result: &Result<Served>) -> Option<bool> {
+    match result {
+        Ok(Served::Absorbed) => None,
+        Err(e) if e.is::<Unauthenticated>() || e.is::<Declined>() => None,
+        result => Some(result.is_ok()),
+    }
+}
+
+/// A proxied client connection.
+#[derive(Clone, Copy)]
+struct Client {
+    id: u64,
+    /// the full address, even with --mask-client-ips
+    peer: SocketAddr,
+}
+
+impl Client {
+    /// The span every log line about this connection is in, so lines from
+    /// concurrent connections can be told apart. `shown` is the peer as
+    /// it may be logged.
+    fn span(&self, shown: &str) -> tracing::Span {
+        tracing::info_span!("connection", id = self.id, peer = %shown)
+    }
+}
+
+/// Log a routine line about a connection at `proxy`'s connection level.
+/// The level has to be known where the event is declared, hence the match.
+macro_rules! connection_event {
+    ($proxy:expr, $($arg:tt)+) => {
+        match $proxy.connection_level {
+            tracing::Level::INFO => info!($($arg)+),
+            _ => debug!($($arg)+),
+        }
+    };
+}
+
+/// Settings and state shared by all proxied connections.
+struct Proxy {
+    name: Option<String>,
+    /// Every address the target resolved to, for probing. Connections go
+    /// to the first one. Replaced whenever --target-srv is looked up again,
+    /// or when connecting fails and --target resolves somewhere new.
+    target_addrs: RwLock<Vec<SocketAddr>>,
+    /// The --target hostname to resolve again when connecting fails, unless
+    /// it is a plain address
+    re_resolve: Option<String>,
+    resolve_timeout: Duration,
+    mac: [u8; 6],
+    timeout: Duration,
+    pre_ping_delay: Duration,
+    connect_timeout: Duration,
+    so_priority: Option<u32>,
+    connect_retry: u32,
+    connect_retry_delay: Duration,
+    /// With --service-ready-timeout, retry until then instead of
+    /// --connect-retry times
+    service_ready_timeout: Option<Duration>,
+    confirm_banner: Option<BannerCheck>,
+    reconnect_backend: bool,
+    linger: Duration,
+    auto_reconnect: bool,
+    absorb_empty_connections: bool,
+    absorb_window: Duration,
+    reconnect_threshold: Duration,
+    rewake_on_connect_fail: bool,
+    no_wait: bool,
+    no_wait_still_wake: bool,
+    progress_protocol: bool,
+    connect_banner: Option<String>,
+    auth_token: Option<String>,
+    handshake_timeout: Duration,
+    mask_client_ips: bool,
+    /// What routine lines about each connection are logged at
+    connection_level: tracing::Level,
+    confirm_count: u32,
+    wake_anomaly_threshold: f64,
+    wake_anomaly_webhook: Option<String>,
+    max_wakes_per_hour: Option<usize>,
+    circuit_breaker: Option<CircuitBreaker>,
+    allow_hours: Vec<HourWindow>,
+    wol_rate_limit: Option<Arc<WolRateLimiter>>,
+    wol_cooldown: Duration,
+    /// When the last magic packet was sent by each wake method
+    last_wake_sent: Mutex<HashMap<WakeMethod, Instant>>,
+    /// When the server was woken, oldest first, covering the last hour.
+    wake_times: Arc<Mutex<VecDeque<Instant>>>,
+    #[cfg(feature = "socks5")]
+    socks5: Option<Socks5>,
+    /// Source ports of the latest server connections, with
+    /// --reuse-port-avoidance
+    recent_ports: Option<Mutex<VecDeque<u16>>>,
+    wake_detect: WakeDetect,
+    ping: PingSettings,
+    /// Interfaces raw Ethernet frames and ARP probes go out of
+    layer2: Vec<Layer2>,
+    /// Interfaces UDP magic packets are sent out of, one packet each
+    wol_interfaces: Vec<String>,
+    wol_ttl: Option<u32>,
+    relay: Option<RelayClient>,
+    esp32_url: Option<String>,
+    ha_webhook: Option<String>,
+    /// Send an ARP probe for the target before each magic packet
+    arp_refresh: bool,
+    strict_mac: bool,
+    /// Wake methods to try, in order
+    wake_chain: Vec<WakeMethod>,
+    method_timeout: Duration,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsAcceptor>,
+    /// Number of connections currently being proxied to the server
+    active_connections: AtomicUsize,
+    /// Connections so far that had to wake the server, and that found it
+    /// already up
+    cold_connections: AtomicU64,
+    warm_connections: AtomicU64,
+    /// Skip waking entirely and go straight to proxying
+    no_wake: AtomicBool,
+    /// Set by MAINT ON: don't wake the server, treating connections that
+    /// find it asleep as failed wakes
+    maintenance: AtomicBool,
+    /// Set by MAINT REFUSE: refuse new connections as well
+    maintenance_refuse: AtomicBool,
+    /// Whether the server was up at the last --monitor-interval check, or
+    /// None if it isn't being monitored
+    known_up: Mutex<Option<bool>>,
+    /// Show live progress while waiting for the server to wake up
+    progress: bool,
+    history: Mutex<WakeHistory>,
+    /// What has been done since startup, for the summary on shutdown.
+    /// Shared with the fallback server.
+    totals: Arc<Totals>,
+    events: Option<Arc<EventLog>>,
+    event_stream: Option<Arc<EventStream>>,
+    #[cfg(all(feature = "tui", unix))]
+    dashboard: Option<Dashboard>,
+    /// Woken and proxied to instead when this server doesn't wake up.
+    fallback: Option<Arc<Proxy>>,
+    dry_run: bool,
+}
+
+/// Running totals since startup, summed up when the proxy shuts down.
+#[derive(Default)]
+struct Totals {
+    bytes_to_server: AtomicU64,
+    bytes_to_client: AtomicU64,
+    /// Magic packets (or relay requests) sent
+    wakes_sent: AtomicU64,
+    /// Wakes that were confirmed, and how long they took altogether
+    wakes_confirmed: AtomicU64,
+    wake_latency_ms: AtomicU64,
+    /// Connections that ended in an error
+    errors: AtomicU64,
+}
+
+impl Totals {
+    /// The line printed on shutdown, for `accepted` connections over
+    /// `uptime`.
+    fn summary(&self, accepted: u64, uptime: Duration) -> String {
+        let confirmed = self.wakes_confirmed.load(Ordering::SeqCst);
+        let latency = match confirmed {
+            0 => "n/a".to_string(),
+            n => format!(
+                "{:.1}s",
+                self.wake_latency_ms.load(Ordering::SeqCst) as f64 / n as f64 / 1000.0
+            ),
+        };
+        format!(
+            "Accepted {} connections, proxied {} in / {} out, {} WoL events, \
+             avg wake latency {}, {} errors. Uptime: {}.",
+            accepted,
+            format_bytes(self.bytes_to_server.load(Ordering::SeqCst)),
+            format_bytes(self.bytes_to_client.load(Ordering::SeqCst)),
+            self.wakes_sent.load(Ordering::SeqCst),
+            latency,
+            self.errors.load(Ordering::SeqCst),
+            humantime::format_duration(Duration::from_secs(uptime.as_secs()))
+        )
+    }
+}
+
+/// A byte count in binary units, e.g. "1.2 GiB".
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Counts a connection as active for as long as it is held.
+struct ActiveConnection<'a>(&'a AtomicUsize);
+
+impl<'a> ActiveConnection<'a> {
+    fn new(count: &'a AtomicUsize) -> Self {
+        count.fetch_add(1, Ordering::SeqCst);
+        Self(count)
+    }
+}
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Proxy {
+    /// Where connections are proxied to.
+    fn target_addr(&self) -> SocketAddr {
+        self.target_addrs.read().unwrap()[0]
+    }
+
+    fn target_addrs(&self) -> Vec<SocketAddr> {
+        self.target_addrs.read().unwrap().clone()
+    }
+
+    /// `addrs` newly found for `name`, without the IPv6 ones if
+    /// --ping-payload is too long to reach them unfragmented. Otherwise
+    /// every ping to them would fail, and the server would never look up.
+    fn pingable(&self, name: &str, mut addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        if let Err(e) = check_ping_payload(&self.ping.payload, &addrs) {
+            warn!("not using the IPv6 addresses of {}: {}", name, e);
+            addrs.retain(SocketAddr::is_ipv4);
+        }
+        addrs
+    }
+
+    /// Whether connections are accepted at this time of day.
+    fn allowed_now(&self) -> bool {
+        let now = Local::now().time();
+        self.allow_hours.is_empty() || self.allow_hours.iter().any(|w| w.contains(now))
+    }
+
+    /// Whether to turn new connection `id` from `shown` away straight
+    /// away, logging why if so.
+    fn refuses(&self, id: u64, shown: &str) -> bool {
+        if !self.allowed_now() {
+            warn!(
+                "Refusing connection from {} outside the --allow-hours windows",
+                shown
+            );
+            return true;
+        }
+        if self.maintenance_refuse.load(Ordering::SeqCst) {
+            warn!(
+                "Refusing connection from {}: {} is in maintenance mode",
+                shown,
+                self.name()
+            );
+            return true;
+        }
+        if self
+            .circuit_breaker
+            .as_ref()
+            .is_some_and(|breaker| !breaker.allow(id))
+        {
+            warn!(
+                "Circuit breaker open for {}, refusing connection from {}",
+                self.name(),
+                shown
+            );
+            return true;
+        }
+        false
+    }
+
+    /// Whether the server is up, as last seen by --monitor-interval or
+    /// else from a quick check.
+    async fn is_up(&self) -> bool {
+        let known_up = *self.known_up.lock().unwrap();
+        match known_up {
+            Some(up) => up,
+            None => self.wait_online(Duration::from_secs(1), 1, false).await,
+        }
+    }
+
+    /// Check once whether the server is up.
+    async fn probe(&self) -> bool {
+        #[cfg(feature = "socks5")]
+        if let Some(socks5) = &self.socks5 {
+            return matches!(
+                tokio::time::timeout(Duration::from_secs(1), socks5.connect(&self.target_addr()))
+                    .await,
+                Ok(Ok(_))
+            );
+        }
+        detect(self.wake_detect, &self.target_addrs(), &self.ping).await
+    }
+
+    /// Wait for the server to come online, timing out after the given
+    /// timeout. The server only counts as online once it has answered
+    /// `confirm_count` probes in a row; a missed reply resets the count.
+    ///
+    /// With `progress` set (and a terminal on stderr), a status line is
+    /// kept updated while waiting.
+    async fn wait_online(&self, timeout: Duration, confirm_count: u32, progress: bool) -> bool {
+        let progress = progress && self.progress;
+        let start = Instant::now();
+        let mut replies = 0;
+        let mut probes = 0;
+        loop {
+            if start.elapsed() >= timeout {
+                if progress {
+                    eprint!("\r\x1b[2K");
+                }
+                return false;
+            }
+            let probe_start = Instant::now();
+            probes += 1;
+            if self.probe().await {
+                replies += 1;
+                if replies >= confirm_count {
+                    if progress {
+                        eprint!("\r\x1b[2K");
+                    }
+                    return true;
+                }
+            } else {
+                replies = 0;
+            }
+            if progress {
+                let eta = match self.history.lock().unwrap().eta() {
+                    Some((eta, samples)) => format!(
+                        ", ETA ~{}s based on last {} wakes",
+                        eta.saturating_sub(start.elapsed()).as_secs(),
+                        samples
+                    ),
+                    None => String::new(),
+                };
+                eprint!(
+                    "\rWaiting for {} to wake... [{}s / {}s max, {} probes sent{}]",
+                    self.name(),
+                    start.elapsed().as_secs(),
+                    timeout.as_secs(),
+                    probes,
+                    eta
+                );
+            }
+            // space out the probes so a host that answers once and then
+            // drops off again is caught, and so a probe failing straight
+            // away doesn't spin, without sleeping past the timeout
+            let spacing = Duration::from_secs(1).saturating_sub(probe_start.elapsed());
+            tokio::time::sleep(spacing.min(timeout.saturating_sub(start.elapsed()))).await;
+        }
+    }
+
+    /// Describe where the magic packet goes, for logging.
+    fn wake_destination(&self) -> String {
+        let destinations: Vec<String> = self
+            .wake_chain
+            .iter()
+            .map(|method| match method {
+                WakeMethod::Layer2 => format!(
+                    "{} on {} (raw Ethernet)",
+                    format_mac(&self.mac),
+                    self.wol_interfaces.join(", ")
+                ),
+                WakeMethod::Wol => format!("{} at {}", format_mac(&self.mac), self.target_addr()),
+                WakeMethod::Relay => format!(
+                    "{} through relay {}",
+                    format_mac(&self.mac),
+                    self.relay.as_ref().map_or("-", |relay| relay.addr.as_str())
+                ),
+                WakeMethod::Esp32 => format!(
+                    "{} through {}",
+                    format_mac(&self.mac),
+                    self.esp32_url.as_deref().unwrap_or("-")
+                ),
+                WakeMethod::Ha => format!(
+                    "{} through {}",
+                    format_mac(&self.mac),
+                    self.ha_webhook.as_deref().unwrap_or("-")
+                ),
+            })
+            .collect();
+        destinations.join(", then ")
+    }
+
+    /// Send the magic packet by `method`, unless one went out that way less
+    /// than --wol-cooldown ago or --wol-rate-limit drops it. Returns whether
+    /// it was sent.
+    async fn send_wake(&self, method: WakeMethod) -> Result<bool> {
+        if let Some(limiter) = &self.wol_rate_limit {
+            if !limiter.acquire().await {
+                return Ok(false);
+            }
+        }
+        // checked after the limiter, which may have held this packet back
+        // while another went out
+        let last_sent = self.last_wake_sent.lock().unwrap().get(&method).copied();
+        if let Some(sent) = last_sent {
+            if sent.elapsed() < self.wol_cooldown {
+                info!(
+                    "Magic packet sent {} ago ({}), not sending another yet",
+                    humantime::format_duration(Duration::from_secs(sent.elapsed().as_secs())),
+                    method
+                );
+                return Ok(false);
+            }
+        }
+        info!(
+            name: kind::WAKE_SENT,
+            "Sending magic packet to {} ({})...",
+            self.name(),
+            method
+        );
+        if let (true, IpAddr::V4(ip)) = (self.arp_refresh, self.target_addr().ip()) {
+            for l2 in &self.layer2 {
+                if let Err(e) = layer2::send_arp_probe(&l2.interface, &l2.src_mac, ip) {
+                    warn!("failed to send ARP probe on {}: {}", l2.interface, e);
+                }
+            }
+        }
+        let sent = match method {
+            WakeMethod::Wol if self.wol_interfaces.is_empty() => {
+                send_magic_packet(&self.mac, &self.target_addr(), None, self.wol_ttl)
+            }
+            WakeMethod::Wol => send_on_each(&self.wol_interfaces, String::as_str, |interface| {
+                send_magic_packet(
+                    &self.mac,
+                    &self.target_addr(),
+                    Some(interface),
+                    self.wol_ttl,
+                )
+            }),
+            WakeMethod::Layer2 if self.layer2.is_empty() => {
+                bail!("layer2 wake needs --wol-interface")
+            }
+            WakeMethod::Layer2 => send_on_each(
+                &self.layer2,
+                |l2| l2.interface.as_str(),
+                |l2| layer2::send_magic_frame(&l2.interface, &self.mac, &l2.src_mac),
+            ),
+            WakeMethod::Relay => match &self.relay {
+                Some(relay) => {
+                    relay
+                        .wake(&self.mac, self.target_addr().ip(), self.timeout)
+                        .await
+                }
+                None => bail!("relay wake needs --relay-to"),
+            },
+            WakeMethod::Esp32 => match &self.esp32_url {
+                Some(url) => self.wake_request("GET", url, None).await,
+                None => bail!("esp32 wake needs --esp32-url"),
+            },
+            WakeMethod::Ha => match &self.ha_webhook {
+                Some(url) => {
+                    let body = serde_json::json!({
+                        "mac": format_mac(&self.mac),
+                        "target_addr": self.target_addr(),
+                    });
+                    self.wake_request("POST", url, Some(&body)).await
+                }
+                None => bail!("ha wake needs --ha-webhook"),
+            },
+        };
+        sent?;
+        // only once it went out, so a failed send can be retried at once
+        self.last_wake_sent
+            .lock()
+            .unwrap()
+            .insert(method, Instant::now());
+        self.totals.wakes_sent.fetch_add(1, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    /// Send one magic packet by the first wake method, without waiting for
+    /// the server. If it goes out, it counts towards --max-wakes-per-hour
+    /// like a whole `wake`.
+    async fn wake_once(&self) -> Result<bool> {
+        self.check_wake_cap()?;
+        let sent = self.send_wake(self.wake_chain[0]).await?;
+        if sent {
+            self.record_wake()?;
+        }
+        Ok(sent)
+    }
+
+    /// Make the HTTP request of the esp32 or ha wake method, giving up
+    /// after --timeout.
+    async fn wake_request(
+        &self,
+        method: &str,
+        url: &str,
+        body: Option<&serde_json::Value>,
+    ) -> Result<()> {
+        match tokio::time::timeout(self.timeout, http_request(method, url, body)).await {
+            Ok(result) => result,
+            Err(_) => bail!("timed out requesting {}", url),
+        }
+    }
+
+    /// Warn about a wake that took much longer than usual, and send the
+    /// --wake-anomaly-webhook alert in the background.
+    fn report_slow_wake(&self, latency: Duration, p90: Duration) {
+        warn!(
+            "waking {} took {} ms, over {} times the usual {} ms (90th percentile)",
+            self.name(),
+            latency.as_millis(),
+            self.wake_anomaly_threshold,
+            p90.as_millis()
+        );
+        let Some(url) = self.wake_anomaly_webhook.clone() else {
+            return;
+        };
+        let body = serde_json::json!({
+            "event": "WakeAnomaly",
+            "name": self.name,
+            "target_addr": self.target_addr(),
+            "latency_ms": latency.as_millis() as u64,
+            "p90_ms": p90.as_millis() as u64,
+            "threshold": self.wake_anomaly_threshold,
+        });
+        tokio::spawn(
+            async move {
+                let post = tokio::time::timeout(Duration::from_secs(10), post_json(&url, &body));
+                match post.await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("failed to send wake anomaly alert: {}", e),
+                    Err(_) => warn!("timed out sending wake anomaly alert"),
+                }
+            }
+            .instrument(tracing::Span::current()),
+        );
+    }
+
+    /// Whether waking has been turned off, by --no-wake or maintenance
+    /// mode. Connections waiting on a wake still get it.
+    fn waking_disabled(&self) -> bool {
+        self.no_wake.load(Ordering::SeqCst) || self.maintenance.load(Ordering::SeqCst)
+    }
+
+    /// Add an event for `client` to the event log, stream and dashboard, if
+    /// there are any.
+    fn event(&self, client: &Client, event: Event) {
+        let target = self.target_addr();
+        let name = self.name.as_deref();
+        let source = show_client(client.peer, self.mask_client_ips);
+        if let Some(stream) = &self.event_stream {
+            stream.record(client.id, &source, target, name, event.clone());
+        }
+        #[cfg(all(feature = "tui", unix))]
+        if let Some(dashboard) = &self.dashboard {
+            dashboard.record(client.id, source.clone(), event.clone());
+        }
+        if let Some(log) = &self.events {
+            log.record(client.id, &source, target, name, event);
+        }
+    }
+
+    /// How the server is referred to in log messages: its --name if it has
+    /// one, otherwise its address.
+    fn name(&self) -> String {
+        match &self.name {
+            Some(name) => format!("'{}'", name),
+            None => self.target_addr().to_string(),
+        }
+    }
+
+    /// Try each wake method in turn until the server comes up, returning
+    /// whether it did. The whole sequence counts once towards
+    /// --max-wakes-per-hour, however many methods it takes.
+    async fn wake(&self) -> Result<bool> {
+        self.record_wake()?;
+        let timeout = match self.wake_chain.len() {
+            1 => self.timeout,
+            _ => self.method_timeout,
+        };
+        for &method in &self.wake_chain {
+            // Send the wake-on-lan packet to the server
+            let sent_at = Instant::now();
+            if let Err(e) = self.send_wake(method).await {
+                warn!("failed to send magic packet ({}): {}", method, e);
+                continue;
+            }
+
+            // Wait for the server to wake up
+            if !self.pre_ping_delay.is_zero() {
+                tokio::time::sleep(self.pre_ping_delay).await;
+            }
+            info!("Waiting for {} to wake up...", self.name());
+            if self.wait_online(timeout, self.confirm_count, true).await {
+                info!(name: kind::WOKE, "{} woke up ({})", self.name(), method);
+                if let Some(up) = self.known_up.lock().unwrap().as_mut() {
+                    *up = true;
+                }
+                let latency = sent_at.elapsed();
+                self.totals.wakes_confirmed.fetch_add(1, Ordering::SeqCst);
+                self.totals
+                    .wake_latency_ms
+                    .fetch_add(latency.as_millis() as u64, Ordering::SeqCst);
+                let p90 = self.history.lock().unwrap().p90();
+                if let Some(p90) =
+                    p90.filter(|&p90| is_slow_wake(latency, p90, self.wake_anomaly_threshold))
+                {
+                    self.report_slow_wake(latency, p90);
+                }
+                let snapshot = {
+                    let mut history = self.history.lock().unwrap();
+                    history.record(latency);
+                    history.snapshot(false)
+                };
+                snapshot.save_in_background();
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    async fn connect(&self) -> Result<TcpStream> {
+        self.connect_to(self.target_addr()).await
+    }
+
+    async fn connect_to(&self, addr: SocketAddr) -> Result<TcpStream> {
+        let connect = async {
+            #[cfg(feature = "socks5")]
+            if let Some(socks5) = &self.socks5 {
+                return socks5.connect(&addr).await;
+            }
+            match &self.recent_ports {
+                Some(recent) => connect_avoiding(addr, recent).await,
+                None => Ok(TcpStream::connect(&addr).await?),
+            }
+        };
+        let stream = match tokio::time::timeout(self.connect_timeout, connect).await {
+            Ok(result) => result?,
+            Err(_) => bail!(
+                "timed out connecting to {} after {} (the server is up, but not accepting connections)",
+                addr,
+                humantime::format_duration(self.connect_timeout)
+            ),
+        };
+        if let Some(priority) = self.so_priority {
+            set_priority(&stream, priority)?;
+        }
+        Ok(stream)
+    }
+
+    /// Compare --mac with the server's ARP entry, which should be fresh
+    /// after it has just answered. A mismatch is only a warning unless
+    /// --strict-mac is set.
+    fn check_arp_mac(&self) -> Result<()> {
+        self.check_arp_mac_of(self.target_addr())
+    }
+
+    /// Like `check_arp_mac`, but for the server at `addr`.
+    fn check_arp_mac_of(&self, addr: SocketAddr) -> Result<()> {
+        let IpAddr::V4(ip) = addr.ip() else {
+            return Ok(());
+        };
+        let Some(actual) = neighbor_mac(ip).ok().flatten() else {
+            return Ok(());
+        };
+        if actual == self.mac {
+            return Ok(());
+        }
+        let message = format!(
+            "{} responded from unexpected MAC {} (--mac is {}): possible ARP spoofing or MAC change",
+            ip,
+            format_mac(&actual),
+            format_mac(&self.mac)
+        );
+        if self.strict_mac {
+            bail!(message);
+        }
+        warn!("{}", message);
+        Ok(())
+    }
+
+    /// After connecting to the known address failed, resolve --target
+    /// again in case the server came back with a different address (e.g. a
+    /// new DHCP lease), and try the new addresses. The first that works,
+    /// and answers ARP with --mac, is used for later connections too.
+    async fn connect_re_resolved(&self, error: anyhow::Error) -> Result<TcpStream> {
+        let Some(target) = &self.re_resolve else {
+            return Err(error);
+        };
+        let old = self.target_addr();
+        let addrs = match resolve_target(target, self.resolve_timeout).await {
+            Ok(addrs) => self.pingable(target, addrs),
+            Err(e) => {
+                warn!("failed to resolve {} again: {}", target, e);
+                return Err(error);
+            }
+        };
+        for (i, &addr) in addrs.iter().enumerate().filter(|(_, &addr)| addr != old) {
+            let Ok(stream) = self.connect_to(addr).await else {
+                continue;
+            };
+            self.check_arp_mac_of(addr)?;
+            info!("{} is now at {}", self.name(), addr);
+            let mut addrs = addrs.clone();
+            addrs.swap(0, i);
+            *self.target_addrs.write().unwrap() = addrs;
+            return Ok(stream);
+        }
+        Err(error)
+    }
+
+    /// With --confirm-banner, wait on a new server connection for the
+    /// banner that shows the service is really ready. Returns the banner
+    /// bytes read, which still need to reach the client.
+    async fn confirm_ready(&self, mut stream: TcpStream) -> Result<(TcpStream, Vec<u8>)> {
+        let Some(check) = &self.confirm_banner else {
+            return Ok((stream, Vec::new()));
+        };
+        let mut banner = vec![0u8; check.max_bytes];
+        let mut len = 0;
+        let read_banner = async {
+            // a banner can arrive in pieces, so keep reading until it
+            // matches or there's no room for more
+            loop {
+                let n = stream.read(&mut banner[len..]).await?;
+                if n == 0 {
+                    bail!("{} closed the connection without a banner", self.name());
+                }
+                len += n;
+                let matches = match &check.pattern {
+                    Some(pattern) => pattern.is_match(&String::from_utf8_lossy(&banner[..len])),
+                    None => true,
+                };
+                if matches {
+                    return Ok(());
+                }
+                if len == banner.len() {
+                    bail!(
+                        "banner from {} doesn't match --banner-regex: {:?}",
+                        self.name(),
+                        String::from_utf8_lossy(&banner[..len])
+                    );
+                }
+            }
+        };
+        match tokio::time::timeout(check.timeout, read_banner).await {
+            Ok(result) => result?,
+            Err(_) => bail!(
+                "no banner from {} within {}",
+                self.name(),
+                humantime::format_duration(check.timeout)
+            ),
+        }
+        banner.truncate(len);
+        Ok((stream, banner))
+    }
+
+    /// Connect to the server, retrying a few times in case it is up but the
+    /// service behind the port hasn't started yet. `up_since` is when the
+    /// server was first seen up; with --service-ready-timeout, retries go
+    /// on until that long after it, and otherwise stop after
+    /// --connect-retry attempts. Returns the connection and
+    /// any banner read from it by --confirm-banner.
+    async fn connect_retrying(&self, up_since: Instant) -> Result<(TcpStream, Vec<u8>)> {
+        let mut attempt = 0;
+        loop {
+            let retry = match self.service_ready_timeout {
+                Some(timeout) => up_since.elapsed() + self.connect_retry_delay < timeout,
+                None => attempt < self.connect_retry,
+            };
+            // only a failure to connect at all can mean the address moved
+            let (result, connected) = match self.connect().await {
+                Ok(stream) => (self.confirm_ready(stream).await, true),
+                Err(e) => (Err(e), false),
+            };
+            match result {
+                Ok(conn) => return Ok(conn),
+                Err(e) if retry => {
+                    attempt += 1;
+                    let of = match self.service_ready_timeout {
+                        Some(_) => String::new(),
+                        None => format!("/{}", self.connect_retry),
+                    };
+                    warn!(
+                        "connecting to {} failed ({}), retry {}{} in {} ({}s since it came up)",
+                        self.name(),
+                        e,
+                        attempt,
+                        of,
+                        humantime::format_duration(self.connect_retry_delay),
+                        up_since.elapsed().as_secs()
+                    );
+                    tokio::time::sleep(self.connect_retry_delay).await;
+                }
+                Err(e) if connected => return Err(e),
+                Err(e) => {
+                    let stream = self.connect_re_resolved(e).await?;
+                    return self.confirm_ready(stream).await;
+                }
+            }
+        }
+    }
+
+    /// Make this fallback share `primary`'s --wol-rate-limit and totals,
+    /// so packets to either server count towards them. Each keeps its own
+    /// --max-wakes-per-hour, or a primary that used it up could never be
+    /// fallen back from.
+    fn share_limits(&mut self, primary: &Proxy) {
+        self.wol_rate_limit = primary.wol_rate_limit.clone();
+        self.totals = primary.totals.clone();
+    }
+
+    /// The times the server was woken in the hour up to `now`, or an error
+    /// if the hourly cap has already been reached. None without a cap.
+    fn wakes_this_hour(&self, now: Instant) -> Result<Option<MutexGuard<'_, VecDeque<Instant>>>> {
+        let Some(cap) = self.max_wakes_per_hour else {
+            return Ok(None);
+        };
+        let hour = Duration::from_secs(60 * 60);
+        let mut times = self.wake_times.lock().unwrap();
+        while times
+            .front()
+            .is_some_and(|&t| now.saturating_duration_since(t) >= hour)
+        {
+            times.pop_front();
+        }
+        if times.len() >= cap {
+            return Err(Declined(format!(
+                "not waking server: already woken {} times in the last hour (--max-wakes-per-hour)",
+                times.len()
+            ))
+            .into());
+        }
+        Ok(Some(times))
+    }
+
+    /// Refuse to wake the server if the hourly cap has already been
+    /// reached.
+    fn check_wake_cap(&self) -> Result<()> {
+        self.wakes_this_hour(Instant::now()).map(drop)
+    }
+
+    /// Count a magic packet that is about to be sent towards the hourly
+    /// cap, or refuse it if the cap has already been reached.
+    fn record_wake(&self) -> Result<()> {
+        self.record_wake_at(Instant::now())
+    }
+
+    fn record_wake_at(&self, now: Instant) -> Result<()> {
+        if let Some(mut times) = self.wakes_this_hour(now)? {
+            times.push_back(now);
+        }
+        Ok(())
+    }
+}
+
+/// Handle a newly accepted connection, first finishing the TLS handshake
+/// with --tls-cert.
+async fn handle_connection(
+    stream: TcpStream,
+    proxy: &Arc<Proxy>,
+    client: &Client,
+    counts: &Arc<ByteCounts>,
+) -> Result<Served> {
+    // one --handshake-timeout for the TLS handshake and auth token together
+    let deadline = tokio::time::Instant::now() + proxy.handshake_timeout;
+    #[cfg(feature = "tls")]
+    if let Some(acceptor) = &proxy.tls {
+        let stream = match tokio::time::timeout_at(deadline, acceptor.accept(stream)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                return Err(Unauthenticated(format!("TLS handshake failed: {}", e)).into())
+            }
+            Err(_) => return Err(Unauthenticated("TLS handshake timed out".to_string()).into()),
+        };
+        return handle_client(stream, proxy, client, counts, deadline).await;
+    }
+    handle_client(stream, proxy, client, counts, deadline).await
+}
+
+/// Handle a connection once any TLS handshake is done, `deadline` being
+/// when its --auth-token must have arrived by.
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    proxy: &Arc<Proxy>,
+    client: &Client,
+    counts: &Arc<ByteCounts>,
+    deadline: tokio::time::Instant,
+) -> Result<Served> {
+    if let Some(token) = &proxy.auth_token {
+        let mut tag = [0u8; auth::TAG_LEN];
+        match tokio::time::timeout_at(deadline, stream.read_exact(&mut tag)).await {
+            Ok(Ok(_)) if auth::verify(token.as_bytes(), &tag, relay::unix_time()) => {}
+            Ok(Ok(_)) => return Err(Unauthenticated("invalid auth token".to_string()).into()),
+            Ok(Err(_)) | Err(_) => {
+                return Err(Unauthenticated("no auth token sent".to_string()).into())
+            }
+        }
+    }
+    if let Some(banner) = &proxy.connect_banner {
+        stream
+            .write_all(format!("{}\r\n", banner).as_bytes())
+            .await?;
+    }
+    serve(stream, proxy, client, counts, Vec::new()).await
+}
+
+/// Wake the server if it needs it and proxy the connection to it, or to
+/// the fallback server if it doesn't wake up. `pending` is anything
+/// already read from the client, to be passed on to the server first.
+/// What is carried either way is added to `counts`.
+async fn serve<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    proxy: &Arc<Proxy>,
+    client: &Client,
+    counts: &Arc<ByteCounts>,
+    mut pending: Vec<u8>,
+) -> Result<Served> {
+    let mut up_since = Instant::now();
+    // whether this connection had to wait for the server to wake up
+    let mut cold = false;
+
+    // Check if the server is already online, and skip WOL if it is:
+    if proxy.no_wake.load(Ordering::SeqCst) {
+        connection_event!(
+            proxy,
+            "Waking is disabled, not checking whether the server is up"
+        );
+    } else if !proxy.is_up().await {
+        if proxy.maintenance.load(Ordering::SeqCst) {
+            proxy.event(
+                client,
+                Event::WakeFailed {
+                    error: "maintenance mode".to_string(),
+                },
+            );
+            if let Some(fallback) = &proxy.fallback {
+                warn!(
+                    "{} is asleep and in maintenance mode, falling back to {}",
+                    proxy.name(),
+                    fallback.name()
+                );
+                return Box::pin(serve(stream, fallback, client, counts, pending)).await;
+            }
+            return Err(Declined(format!(
+                "{} is asleep and in maintenance mode, closing connection",
+                proxy.name()
+            ))
+            .into());
+        }
+        if proxy.absorb_empty_connections && pending.is_empty() {
+            let mut buf = vec![0u8; 8192];
+            match tokio::time::timeout(proxy.absorb_window, stream.read(&mut buf)).await {
+                Ok(Ok(0)) | Err(_) => {
+                    connection_event!(
+                        proxy,
+                        "Client sent nothing, closing without waking {}",
+                        proxy.name()
+                    );
+                    proxy.event(
+                        client,
+                        Event::ConnectionClosed {
+                            bytes_to_server: Some(0),
+                            bytes_to_client: Some(0),
+                            error: None,
+                        },
+                    );
+                    return Ok(Served::Absorbed);
+                }
+                Ok(Ok(n)) => {
+                    buf.truncate(n);
+                    pending = buf;
+                }
+                Ok(Err(e)) => return Err(e.into()),
+            }
+        }
+        if proxy.no_wait {
+            if proxy.no_wait_still_wake {
+                // start it booting for whoever connects next, without
+                // holding up this client's rejection
+                let (proxy, client) = (proxy.clone(), *client);
+                tokio::spawn(
+                    async move {
+                        match proxy.wake_once().await {
+                            Ok(true) => proxy.event(
+                                &client,
+                                Event::WakeSent {
+                                    methods: vec![proxy.wake_chain[0].to_string()],
+                                },
+                            ),
+                            Ok(false) => {}
+                            Err(e) => warn!("failed to send magic packet: {}", e),
+                        }
+                    }
+                    .instrument(tracing::Span::current()),
+                );
+            }
+            return Err(Declined(format!(
+                "{} is asleep, closing connection (--no-wait)",
+                proxy.name()
+            ))
+            .into());
+        }
+        proxy.check_wake_cap()?;
+        let wake_start = Instant::now();
+        proxy.event(
+            client,
+            Event::WakeSent {
+                methods: proxy.wake_chain.iter().map(ToString::to_string).collect(),
+            },
+        );
+        let woke = if proxy.progress_protocol {
+            wake_reporting_progress(&mut stream, proxy).await?
+        } else {
+            proxy.wake().await?
+        };
+        if !woke {
+            proxy.event(
+                client,
+                Event::WakeFailed {
+                    error: "did not wake up in time".to_string(),
+                },
+            );
+            if let Some(fallback) = &proxy.fallback {
+                warn!(
+                    "{} did not wake up in time, falling back to {}",
+                    proxy.name(),
+                    fallback.name()
+                );
+                return Box::pin(serve(stream, fallback, client, counts, pending)).await;
+            }
+            if proxy.progress_protocol {
+                stream.write_all(b"failed\n").await?;
+            }
+            bail!("Server did not wake up in time");
+        }
+        proxy.event(
+            client,
+            Event::WakeConfirmed {
+                latency_ms: wake_start.elapsed().as_millis() as u64,
+            },
+        );
+        up_since = Instant::now();
+        cold = true;
+        proxy.check_arp_mac()?;
+    }
+    let counter = if cold {
+        &proxy.cold_connections
+    } else {
+        &proxy.warm_connections
+    };
+    counter.fetch_add(1, Ordering::SeqCst);
+    if proxy.progress_protocol {
+        stream.write_all(b"ready\n").await?;
+    }
+
+    // Proxy the connection to the server
+    connection_event!(
+        proxy,
+        name: kind::CONNECTION,
+        "Proxying connection to {}...",
+        proxy.name()
+    );
+    let (mut server_conn, banner) = match proxy.connect_retrying(up_since).await {
+        Ok(conn) => conn,
+        Err(e) if proxy.rewake_on_connect_fail && !proxy.waking_disabled() => {
+            warn!(
+                "connecting to {} failed ({}), sending the wake sequence again",
+                proxy.name(),
+                e
+            );
+            proxy.check_wake_cap()?;
+            let wake_start = Instant::now();
+            proxy.event(
+                client,
+                Event::WakeSent {
+                    methods: proxy.wake_chain.iter().map(ToString::to_string).collect(),
+                },
+            );
+            if !proxy.wake().await? {
+                proxy.event(
+                    client,
+                    Event::WakeFailed {
+                        error: "did not wake up in time".to_string(),
+                    },
+                );
+                bail!("Server did not wake up in time after connecting failed");
+            }
+            proxy.event(
+                client,
+                Event::WakeConfirmed {
+                    latency_ms: wake_start.elapsed().as_millis() as u64,
+                },
+            );
+            proxy.check_arp_mac()?;
+            proxy.connect_retrying(Instant::now()).await?
+        }
+        Err(e) => return Err(e),
+    };
+    server_conn.write_all(&pending).await?;
+    stream.write_all(&banner).await?;
+    counts.add(pending.len() as u64, banner.len() as u64);
+    #[cfg(all(feature = "tui", unix))]
+    if let Some(dashboard) = &proxy.dashboard {
+        dashboard.transfer(client.id, counts.clone());
+    }
+    let mut stream = Counted::new(stream, counts.clone());
+    let _active = ActiveConnection::new(&proxy.active_connections);
+    let copied = if proxy.reconnect_backend || proxy.auto_reconnect {
+        copy_reconnecting(proxy, &mut server_conn, &mut stream).await
+    } else {
+        match tokio::io::copy_bidirectional(&mut server_conn, &mut stream).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    };
+    // even after a failed copy, what was already sent should still get
+    // through
+    if !proxy.linger.is_zero() {
+        close_lingering(server_conn, proxy.linger, proxy.name());
+    }
+    // what was carried counts towards the totals even if the copy failed
+    let (to_server, to_client) = (counts.to_server(), counts.to_client());
+    proxy
+        .totals
+        .bytes_to_server
+        .fetch_add(to_server, Ordering::SeqCst);
+    proxy
+        .totals
+        .bytes_to_client
+        .fetch_add(to_client, Ordering::SeqCst);
+    copied?;
+    connection_event!(
+        proxy,
+        "Connection closed ({} start, {} bytes to server, {} bytes to client)",
+        if cold { "cold" } else { "warm" },
+        to_server,
+        to_client
+    );
+    proxy.event(
+        client,
+        Event::ConnectionClosed {
+            bytes_to_server: Some(to_server),
+            bytes_to_client: Some(to_client),
+            error: None,
+        },
+    );
+
+    // Done!
+    Ok(Served::Proxied)
+}
+
+/// How often `--progress-protocol` clients are told the server is still
+/// waking up.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Wake the server, writing a `waking... Ns` line to the client every
+/// `PROGRESS_INTERVAL` until it is up.
+async fn wake_reporting_progress<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    proxy: &Proxy,
+) -> Result<bool> {
+    let start = Instant::now();
+    let wake = proxy.wake();
+    tokio::pin!(wake);
+    let mut ticker = tokio::time::interval_at(
+        tokio::time::Instant::now() + PROGRESS_INTERVAL,
+        PROGRESS_INTERVAL,
+    );
+    loop {
+        tokio::select! {
+            woke = &mut wake => return woke,
+            _ = ticker.tick() => {
+                let line = format!("waking... {}s\n", start.elapsed().as_secs());
+                if let Err(e) = stream.write_all(line.as_bytes()).await {
+                    return Err(Declined(format!("client went away during the wake: {}", e)).into());
+                }
+            }
+        }
+    }
+}
+
+/// Like `copy_bidirectional`, but if the connection to the server fails
+/// (or, with `--reconnect-backend`, the server closes it while the client
+/// is still sending) it is re-established (up to `MAX_RECONNECTS` times)
+/// and proxying carries on. Anything in flight when the connection
+/// dropped is lost.
+///
+/// With `--auto-reconnect`, a failure within `--reconnect-threshold` of
+/// connecting is taken to mean the server went down, and it is woken again
+/// before reconnecting.
+async fn copy_reconnecting<S: AsyncRead + AsyncWrite + Unpin>(
+    proxy: &Proxy,
+    server_conn: &mut TcpStream,
+    client: &mut S,
+) -> Result<()> {
+    let mut client_buf = vec![0u8; 8192];
+    let mut server_buf = vec![0u8; 8192];
+    let mut client_open = true;
+    let mut reconnects = 0;
+    let connected_at = Instant::now();
+    loop {
+        let server_result = tokio::select! {
+            read = client.read(&mut client_buf), if client_open => {
+                match read? {
+                    0 => {
+                        client_open = false;
+                        server_conn.shutdown().await
+                    }
+                    n => server_conn.write_all(&client_buf[..n]).await,
+                }
+            }
+            read = server_conn.read(&mut server_buf) => match read {
+                // a service restarting may close the connection cleanly
+                // rather than reset it
+                Ok(0) if client_open && proxy.reconnect_backend && reconnects < MAX_RECONNECTS => {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "server closed the connection",
+                    ))
+                }
+                Ok(0) => {
+                    client.shutdown().await?;
+                    return Ok(());
+                }
+                Ok(n) => {
+                    client.write_all(&server_buf[..n]).await?;
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = server_result {
+            if !client_open || reconnects == MAX_RECONNECTS {
+                return Err(e.into());
+            }
+            reconnects += 1;
+            // with waking disabled, only a plain reconnect is left to try
+            if proxy.auto_reconnect
+                && connected_at.elapsed() <= proxy.reconnect_threshold
+                && !proxy.waking_disabled()
+            {
+                warn!(
+                    "connection to server failed {} into the session ({}), waking it again...",
+                    humantime::format_duration(Duration::from_secs(
+                        connected_at.elapsed().as_secs()
+                    )),
+                    e
+                );
+                proxy.check_wake_cap()?;
+                if !proxy.wake().await? {
+                    bail!("Server did not wake up in time after the connection failed");
+                }
+                proxy.check_arp_mac()?;
+                // the client already has the banner from the first
+                // connection
+                *server_conn = proxy.connect_retrying(Instant::now()).await?.0;
+            } else if proxy.reconnect_backend {
+                warn!("connection to server failed ({}), reconnecting...", e);
+                *server_conn = proxy.connect().await?;
+            } else {
+                return Err(e.into());
+            }
+        }
+    }
+}
+
+/// Close a server connection with SO_LINGER set, so the close waits up to
+/// `linger` for unsent data to be delivered. That wait blocks, so it
+/// happens off the runtime.
+fn close_lingering(conn: TcpStream, linger: Duration, name: String) {
+    let Ok(conn) = conn.into_std() else {
+        return;
+    };
+    if let Err(e) = SockRef::from(&conn).set_linger(Some(linger)) {
+        warn!(
+            "failed to set SO_LINGER on the connection to {}: {}",
+            name, e
+        );
+        return;
+    }
+    let span = tracing::Span::current();
+    tokio::task::spawn_blocking(move || {
+        let start = Instant::now();
+        drop(conn);
+        if start.elapsed() >= linger {
+            span.in_scope(|| {
+                warn!(
+                    "the connection to {} was closed before its last data was delivered (--linger {})",
+                    name,
+                    humantime::format_duration(linger)
+                )
+            });
+        }
+    });
+}
+
+/// How many times a session may reconnect to the server with
+/// `--reconnect-backend` or `--auto-reconnect` before giving up.
+const MAX_RECONNECTS: u32 = 3;
+
+/// Handle a single connection to the relay: read one signed request,
+/// check it and send the magic packet.
+async fn handle_relay_request(
+    mut stream: TcpStream,
+    verifier: &Mutex<Verifier>,
+    broadcast: &SocketAddr,
+    confirm_timeout: Duration,
+    ping: &PingSettings,
+) -> Result<()> {
+    let (body, tag) =
+        tokio::time::timeout(Duration::from_secs(5), relay::read_request(&mut stream)).await??;
+    let req = verifier
+        .lock()
+        .unwrap()
+        .verify(&body, &tag, relay::unix_time());
+    let result = match req {
+        Ok(req) => relay_wake(&req, broadcast, confirm_timeout, ping).await,
+        Err(e) => Err(e),
+    };
+
+    let resp = WakeResponse {
+        success: result.is_ok(),
+        error: result.as_ref().err().map(|e| e.to_string()),
+        latency_ms: result.as_ref().ok().copied().flatten(),
+    };
+    relay::write_response(&mut stream, &resp).await?;
+    result.map(|_| ())
+}
+
+/// Send the magic packet for a verified request and, if it asks for
+/// confirmation, wait for the target to answer pings. Returns how long
+/// that took.
+async fn relay_wake(
+    req: &WakeRequest,
+    broadcast: &SocketAddr,
+    confirm_timeout: Duration,
+    settings: &PingSettings,
+) -> Result<Option<u64>> {
+    let mac = parse_mac(&req.mac)?;
+    // the target is only known now, so an IPv6 one may be out of reach
+    // of the relay's --ping-payload
+    if let Some(target) = req.target.filter(|_| req.confirm) {
+        check_ping_payload(&settings.payload, &[SocketAddr::new(target, 0)])?;
+    }
+    let mut dest = *broadcast;
+    if let Some(port) = req.port {
+        dest.set_port(port);
+    }
+    info!(name: kind::WAKE_SENT, "Relaying magic packet for {}...", req.mac);
+    let sent_at = Instant::now();
+    send_magic_packet(&mac, &dest, None, None)?;
+    if !req.confirm {
+        return Ok(None);
+    }
+    let Some(target) = req.target else {
+        bail!("confirmation needs the target address");
+    };
+    while sent_at.elapsed() < confirm_timeout {
+        let probe_start = Instant::now();
+        if ping(&[target], settings).await {
+            return Ok(Some(sent_at.elapsed().as_millis() as u64));
+        }
+        tokio::time::sleep(Duration::from_secs(1).saturating_sub(probe_start.elapsed())).await;
+    }
+    bail!(
+        "{} did not answer pings within {}",
+        target,
+        humantime::format_duration(confirm_timeout)
+    )
+}
+
+async fn run_relay(args: RelayArgs) -> Result<()> {
+    args.log.init("wol")?;
+    let verifier = Arc::new(Mutex::new(Verifier::new(
+        read_key(&args.key_file)?,
+        args.max_skew,
+    )));
+    let broadcast = SocketAddr::from_str(&args.broadcast)?;
+    let ping = Arc::new(ping_settings(&args.ping));
+
+    let listener = TcpListener::bind(&args.bind).await?;
+    privs::drop_privileges(&args.privileges)?;
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let addr = canonical_peer(addr);
+        let verifier = verifier.clone();
+        let ping = ping.clone();
+        tokio::spawn(async move {
+            let result =
+                handle_relay_request(stream, &verifier, &broadcast, args.confirm_timeout, &ping)
+                    .await;
+            if let Err(e) = result {
+                warn!("rejected wake request from {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Wake the server at each time in `schedule`, independent of traffic.
+/// With `confirm`, wait for it to come up rather than just sending the
+/// magic packet.
+async fn warm_schedule(proxy: Arc<Proxy>, schedule: Schedule, confirm: bool) {
+    for next in schedule.upcoming(Local) {
+        let wait = (next - Local::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+        if proxy.waking_disabled() {
+            info!("Skipping scheduled wake at {}: waking is disabled", next);
+            continue;
+        }
+        if proxy.probe().await {
+            info!("Scheduled wake at {}: {} is already up", next, proxy.name());
+            continue;
+        }
+        if let Err(e) = proxy.check_wake_cap() {
+            warn!("Skipping scheduled wake at {}: {}", next, e);
+            continue;
+        }
+        info!("Scheduled wake of {} at {}", proxy.name(), next);
+        if !confirm {
+            if let Err(e) = proxy.wake_once().await {
+                warn!("failed to send magic packet: {}", e);
+            }
+        } else {
+            match proxy.wake().await {
+                Ok(true) => {}
+                Ok(false) => warn!("Server did not wake up in time for scheduled wake"),
+                Err(e) => warn!("Skipping scheduled wake at {}: {}", next, e),
+            }
+        }
+    }
+}
+
+/// Keep track of whether the server is up, so connections don't have to
+/// check first.
+async fn monitor(proxy: Arc<Proxy>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let up = proxy.probe().await;
+        let mut known_up = proxy.known_up.lock().unwrap();
+        if known_up.is_some_and(|was_up| was_up != up) {
+            info!("{} is now {}", proxy.name(), if up { "up" } else { "down" });
+        }
+        *known_up = Some(up);
+    }
+}
+
+/// Keep the --tui dashboard's target state current.
+#[cfg(all(feature = "tui", unix))]
+async fn watch_target(proxy: Arc<Proxy>, dashboard: Dashboard) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        ticker.tick().await;
+        dashboard.target_up(proxy.probe().await);
+    }
+}
+
+/// Answer signed control packets on `socket`. Packets that fail
+/// verification get no reply.
+async fn control_channel(proxy: Arc<Proxy>, socket: UdpSocket, mut verifier: control::Verifier) {
+    let socket = Arc::new(socket);
+    let mut buf = [0u8; 512];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("failed to receive control packet: {}", e);
+                continue;
+            }
+        };
+        let command = match verifier.verify(&buf[..len], relay::unix_time()) {
+            Ok(command) => command,
+            Err(e) => {
+                warn!(
+                    "rejected control packet from {}: {}",
+                    show_client(peer, proxy.mask_client_ips),
+                    e
+                );
+                continue;
+            }
+        };
+        let reply = match command.as_str() {
+            "WAKE" => {
+                info!(
+                    "Control packet from {}: waking {}",
+                    show_client(peer, proxy.mask_client_ips),
+                    proxy.name()
+                );
+                // answered once the packet is out, which under
+                // --wol-rate-limit or with a relay can take a while, so it
+                // mustn't hold up the commands behind it
+                let (proxy, socket) = (proxy.clone(), socket.clone());
+                tokio::spawn(async move {
+                    let reply = match proxy.wake_once().await {
+                        Ok(true) => "OK".to_string(),
+                        Ok(false) => "SKIPPED cooldown/rate limit".to_string(),
+                        Err(e) => format!("ERROR {}", e),
+                    };
+                    answer_control(&proxy, &socket, peer, &reply).await;
+                });
+                continue;
+            }
+            "STATUS" => serde_json::json!({
+                "active_connections": proxy.active_connections.load(Ordering::SeqCst),
+                "cold_connections_total": proxy.cold_connections.load(Ordering::SeqCst),
+                "warm_connections_total": proxy.warm_connections.load(Ordering::SeqCst),
+                "wake_stats": proxy.history.lock().unwrap().stats(),
+                // as last seen by --monitor-interval, as a probe could take
+                // a while to give up on a server that's down
+                "target": match *proxy.known_up.lock().unwrap() {
+                    Some(true) => "up",
+                    Some(false) => "down",
+                    None => "unknown",
+                },
+                "maintenance": match (
+                    proxy.maintenance.load(Ordering::SeqCst),
+                    proxy.maintenance_refuse.load(Ordering::SeqCst),
+                ) {
+                    (_, true) => "refuse",
+                    (true, false) => "on",
+                    (false, false) => "off",
+                },
+            })
+            .to_string(),
+            "RESET STATS" => {
+                info!(
+                    "Control packet from {}: resetting wake statistics for {}",
+                    show_client(peer, proxy.mask_client_ips),
+                    proxy.name()
+                );
+                let snapshot = {
+                    let mut history = proxy.history.lock().unwrap();
+                    history.reset();
+                    history.snapshot(false)
+                };
+                let saved = tokio::task::spawn_blocking(|| snapshot.save()).await;
+                match saved.unwrap_or_else(|e| Err(e.into())) {
+                    Ok(()) => "OK".to_string(),
+                    Err(e) => format!("ERROR {}", e),
+                }
+            }
+            "MAINT ON" | "MAINT REFUSE" | "MAINT OFF" => {
+                let (on, refuse) = match command.as_str() {
+                    "MAINT ON" => (true, false),
+                    "MAINT REFUSE" => (true, true),
+                    _ => (false, false),
+                };
+                proxy.maintenance.store(on, Ordering::SeqCst);
+                proxy.maintenance_refuse.store(refuse, Ordering::SeqCst);
+                info!(
+                    "Control packet from {}: maintenance mode {} for {}{}",
+                    show_client(peer, proxy.mask_client_ips),
+                    if on { "on" } else { "off" },
+                    proxy.name(),
+                    if refuse {
+                        ", refusing new connections"
+                    } else {
+                        ""
+                    }
+                );
+                "OK".to_string()
+            }
+            _ => format!("ERROR unknown command {}", command),
+        };
+        answer_control(&proxy, &socket, peer, &reply).await;
+    }
+}
+
+/// Send `reply` to the control packet from `peer`.
+async fn answer_control(proxy: &Proxy, socket: &UdpSocket, peer: SocketAddr, reply: &str) {
+    if let Err(e) = socket.send_to(reply.as_bytes(), peer).await {
+        warn!(
+            "failed to answer control packet from {}: {}",
+            show_client(peer, proxy.mask_client_ips),
+            e
+        );
+    }
+}
+
+/// Keep the server awake while connections are open by re-sending the
+/// magic packet whenever it stops answering.
+async fn keepalive_wake(proxy: Arc<Proxy>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if proxy.active_connections.load(Ordering::SeqCst) == 0
+            || proxy.waking_disabled()
+            || proxy.probe().await
+        {
+            continue;
+        }
+        info!(
+            "{} stopped responding during an active session, waking it again...",
+            proxy.name()
+        );
+        if let Err(e) = proxy.wake_once().await {
+            warn!("failed to send magic packet: {}", e);
+        }
+    }
+}
+
+/// Compare the configured MAC with the one the server answers ARP with,
+/// catching a mistyped --mac before it causes wakes that never work.
+async fn verify_mac(proxy: &Proxy, verify: VerifyMac) -> Result<()> {
+    verify_mac_with(proxy, verify, neighbor_mac).await
+}
+
+/// Like `verify_mac`, but looking the server up in the ARP table with
+/// `neighbor_mac`.
+async fn verify_mac_with(
+    proxy: &Proxy,
+    verify: VerifyMac,
+    neighbor_mac: impl FnOnce(Ipv4Addr) -> Result<Option<[u8; 6]>>,
+) -> Result<()> {
+    let IpAddr::V4(ip) = proxy.target_addr().ip() else {
+        info!("Not verifying the MAC address: ARP only covers IPv4 targets");
+        return Ok(());
+    };
+    if !proxy.probe().await {
+        info!("Not verifying the MAC address: {} is not up", proxy.name());
+        return Ok(());
+    }
+    let actual = match neighbor_mac(ip)? {
+        Some(actual) if actual == proxy.mac => return Ok(()),
+        Some(actual) => actual,
+        None => {
+            info!(
+                "Not verifying the MAC address: {} is not in the ARP table",
+                ip
+            );
+            return Ok(());
+        }
+    };
+    let message = format!(
+        "--mac is {} but {} answers ARP as {}",
+        format_mac(&proxy.mac),
+        ip,
+        format_mac(&actual)
+    );
+    match verify {
+        VerifyMac::Warn => {
+            warn!("{}", message);
+            Ok(())
+        }
+        VerifyMac::Fail => bail!(message),
+    }
+}
+
+/// Resolve a host:port target to all of its addresses, giving up after
+/// `timeout`.
+async fn resolve_target(target: &str, timeout: Duration) -> Result<Vec<SocketAddr>> {
+    resolve_within(target, timeout, lookup_host(target)).await
+}
+
+/// Collect the addresses `lookup` resolves `target` to, leaving out
+/// repeats, unless it takes longer than `timeout`.
+async fn resolve_within<A: Iterator<Item = SocketAddr>>(
+    target: &str,
+    timeout: Duration,
+    lookup: impl std::future::Future<Output = std::io::Result<A>>,
+) -> Result<Vec<SocketAddr>> {
+    match tokio::time::timeout(timeout, lookup).await {
+        Ok(addrs) => {
+            let mut resolved: Vec<SocketAddr> = Vec::new();
+            for addr in addrs? {
+                if !resolved.contains(&addr) {
+                    resolved.push(addr);
+                }
+            }
+            if resolved.is_empty() {
+                bail!("{} did not resolve to any addresses", target);
+            }
+            Ok(resolved)
+        }
+        Err(_) => bail!(
+            "timed out resolving {} after {}",
+            target,
+            humantime::format_duration(timeout)
+        ),
+    }
+}
+
+/// Resolve --target, or look up --target-srv.
+async fn resolve_configured(args: &ProxyArgs) -> Result<Vec<SocketAddr>> {
+    match (&args.target, &args.target_srv) {
+        (_, Some(name)) => resolve_srv(&srv::system_resolver()?, name, args.resolve_timeout).await,
+        (Some(target), None) => resolve_target(target, args.resolve_timeout).await,
+        (None, None) => unreachable!("clap requires --target or --target-srv"),
+    }
+}
+
+async fn resolve_srv(
+    resolver: &TokioResolver,
+    name: &str,
+    timeout: Duration,
+) -> Result<Vec<SocketAddr>> {
+    match tokio::time::timeout(timeout, srv::resolve(resolver, name)).await {
+        Ok(addrs) => addrs,
+        Err(_) => bail!(
+            "timed out looking up {} after {}",
+            name,
+            humantime::format_duration(timeout)
+        ),
+    }
+}
+
+/// Look the SRV record up again every `interval` with `resolver`, so the
+/// proxy follows the service when it moves. Lookup failures keep the
+/// previous addresses.
+async fn srv_refresh(
+    proxy: Arc<Proxy>,
+    resolver: TokioResolver,
+    name: String,
+    interval: Duration,
+    timeout: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    // the first tick completes immediately, and run_proxy has just resolved
+    ticker.tick().await;
     loop {
-        let (stream, _) = listener.accept().await?;
+        ticker.tick().await;
+        let addrs = match resolve_srv(&resolver, &name, timeout).await {
+            Ok(addrs) => proxy.pingable(&name, addrs),
+            Err(e) => {
+                warn!("failed to look up {}: {}", name, e);
+                continue;
+            }
+        };
+        if addrs.is_empty() {
+            continue;
+        }
+        let mut current = proxy.target_addrs.write().unwrap();
+        if *current != addrs {
+            info!("{} now points at {}", name, addrs[0]);
+            *current = addrs;
+        }
+    }
+}
+
+/// Close a connection with a RST instead of the usual FIN.
+fn reset(stream: TcpStream) {
+    // a zero linger makes the close send a RST
+    let _ = SockRef::from(&stream).set_linger(Some(Duration::ZERO));
+}
+
+/// Resolve the target and set up everything needed to wake and proxy to
+/// it, apart from the fallback server.
+async fn build_proxy(
+    args: ProxyArgs,
+    ping: &PingArgs,
+    events: Option<Arc<EventLog>>,
+    event_stream: Option<Arc<EventStream>>,
+) -> Result<Proxy> {
+    // parse mac address:
+    let mac = parse_mac(&args.mac)?;
+    if args.require_unicast_mac {
+        check_unicast(&mac)?;
+    }
+
+    if let Some(url) = &args.wake_anomaly_webhook {
+        if !url.starts_with("http://") {
+            bail!("--wake-anomaly-webhook only supports http:// URLs");
+        }
+    }
+    if args.so_priority.is_some() && !cfg!(target_os = "linux") {
+        bail!("--so-priority is only supported on Linux");
+    }
+
+    // resolve the target address:
+    let target_addrs = resolve_configured(&args).await?;
+    let target_addr = target_addrs[0];
+    check_ping_payload(&ping.ping_payload.0, &target_addrs)?;
+
+    let wake_chain = match (args.wake_chain.is_empty(), args.wol_layer2) {
+        (false, _) => args.wake_chain,
+        (true, true) => vec![WakeMethod::Layer2],
+        (true, false) if args.relay_to.is_some() => vec![WakeMethod::Relay],
+        (true, false) => vec![WakeMethod::Wol],
+    };
+    if wake_chain.contains(&WakeMethod::Relay) && args.relay_to.is_none() {
+        bail!("the relay wake method needs --relay-to");
+    }
+    if wake_chain.contains(&WakeMethod::Esp32) && args.esp32_url.is_none() {
+        bail!("the esp32 wake method needs --esp32-url");
+    }
+    if wake_chain.contains(&WakeMethod::Ha) && args.ha_webhook.is_none() {
+        bail!("the ha wake method needs --ha-webhook");
+    }
+    for (flag, url) in [
+        ("--esp32-url", &args.esp32_url),
+        ("--ha-webhook", &args.ha_webhook),
+    ] {
+        if url.as_ref().is_some_and(|url| !url.starts_with("http://")) {
+            bail!("{} only supports http:// URLs", flag);
+        }
+    }
+    let uses_layer2 = wake_chain.contains(&WakeMethod::Layer2);
+    if args.arp_refresh && !target_addr.is_ipv4() {
+        bail!("--arp-refresh needs an IPv4 target");
+    }
+    if uses_layer2 && args.wol_interface.is_empty() {
+        bail!("the layer2 wake method needs --wol-interface");
+    }
+    let mut layer2 = Vec::new();
+    if uses_layer2 || args.arp_refresh {
+        for interface in &args.wol_interface {
+            let src_mac = match &args.src_mac {
+                Some(mac) => parse_mac(mac)?,
+                None => interface_mac(interface)?,
+            };
+            layer2.push(Layer2 {
+                interface: interface.clone(),
+                src_mac,
+            });
+        }
+    }
+
+    Ok(Proxy {
+        name: args.name,
+        target_addrs: RwLock::new(target_addrs),
+        re_resolve: args
+            .target
+            .clone()
+            .filter(|target| SocketAddr::from_str(target).is_err()),
+        resolve_timeout: args.resolve_timeout,
+        mac,
+        timeout: args.timeout,
+        pre_ping_delay: args.pre_ping_delay,
+        connect_timeout: args.connect_timeout,
+        so_priority: args.so_priority,
+        connect_retry: args.connect_retry,
+        connect_retry_delay: args.connect_retry_delay,
+        service_ready_timeout: args.service_ready_timeout,
+        confirm_banner: args.confirm_banner.then(|| BannerCheck {
+            pattern: args.banner_regex,
+            max_bytes: args.banner_bytes,
+            timeout: args.banner_timeout,
+        }),
+        reconnect_backend: args.reconnect_backend,
+        linger: args.linger,
+        auto_reconnect: args.auto_reconnect,
+        absorb_empty_connections: args.absorb_empty_connections,
+        absorb_window: args.absorb_window,
+        reconnect_threshold: args.reconnect_threshold,
+        rewake_on_connect_fail: args.rewake_on_connect_fail,
+        no_wait: args.no_wait,
+        no_wait_still_wake: args.no_wait_still_wake,
+        progress_protocol: args.progress_protocol,
+        connect_banner: args.connect_banner,
+        auth_token: args.auth_token,
+        handshake_timeout: args.handshake_timeout,
+        mask_client_ips: args.mask_client_ips,
+        connection_level: match args.no_log_connections {
+            true => tracing::Level::DEBUG,
+            false => tracing::Level::INFO,
+        },
+        confirm_count: args.confirm_count,
+        wake_anomaly_threshold: args.wake_anomaly_threshold,
+        wake_anomaly_webhook: args.wake_anomaly_webhook,
+        max_wakes_per_hour: args.max_wakes_per_hour,
+        circuit_breaker: args
+            .circuit_breaker_threshold
+            .map(|threshold| CircuitBreaker::new(threshold, args.circuit_breaker_reset)),
+        allow_hours: args.allow_hours,
+        wol_rate_limit: args
+            .wol_rate_limit
+            .map(|per_minute| WolRateLimiter::start(per_minute, args.wol_queue_depth)),
+        wol_cooldown: args.wol_cooldown,
+        last_wake_sent: Mutex::default(),
+        wake_times: Arc::new(Mutex::new(VecDeque::new())),
+        #[cfg(feature = "socks5")]
+        socks5: args.socks5.map(|addr| Socks5 {
+            addr,
+            auth: args.socks5_user.zip(args.socks5_password),
+        }),
+        recent_ports: args
+            .reuse_port_avoidance
+            .then(|| Mutex::new(VecDeque::new())),
+        wake_detect: args.wake_detect,
+        ping: ping_settings(ping),
+        layer2,
+        wol_interfaces: args.wol_interface,
+        wol_ttl: args.wol_ttl,
+        relay: match args.relay_to.zip(args.relay_key_file) {
+            Some((addr, key_file)) => Some(RelayClient {
+                addr,
+                key: read_key(&key_file)?,
+                wol_port: args.relay_wol_port,
+                confirm: args.relay_confirm,
+            }),
+            None => None,
+        },
+        esp32_url: args.esp32_url,
+        ha_webhook: args.ha_webhook,
+        arp_refresh: args.arp_refresh,
+        strict_mac: args.strict_mac,
+        wake_chain,
+        method_timeout: args.method_timeout.unwrap_or(args.timeout),
+        #[cfg(feature = "tls")]
+        tls: match args.tls_cert.zip(args.tls_key) {
+            Some((cert, key)) => Some(tls::acceptor(&cert, &key, args.client_ca.as_deref())?),
+            None => None,
+        },
+        active_connections: AtomicUsize::new(0),
+        cold_connections: AtomicU64::new(0),
+        warm_connections: AtomicU64::new(0),
+        no_wake: AtomicBool::new(args.no_wake),
+        maintenance: AtomicBool::new(false),
+        maintenance_refuse: AtomicBool::new(false),
+        known_up: Mutex::new(None),
+        progress: !args.quiet && std::io::stderr().is_terminal(),
+        history: Mutex::new(WakeHistory::load(args.state_file)?),
+        totals: Arc::new(Totals::default()),
+        events,
+        event_stream,
+        #[cfg(all(feature = "tui", unix))]
+        dashboard: None,
+        fallback: None,
+        dry_run: args.dry_run,
+    })
+}
+
+async fn run_proxy(args: ProxyArgs, ping: &PingArgs, privileges: &PrivilegeArgs) -> Result<()> {
+    let pings = matches!(args.wake_detect, WakeDetect::Icmp) && !args.uses_socks5();
+
+    // the fallback server shares the event log and stream, so its events
+    // carry on the same connection
+    let events = match &args.event_log {
+        Some(path) => Some(Arc::new(EventLog::open(path)?)),
+        None => None,
+    };
+    let event_stream = match args.events_bind {
+        Some(addr) => Some(Arc::new(EventStream::serve(TcpListener::bind(addr).await?))),
+        None => None,
+    };
+    let mut fallback = match args.fallback_target.clone().zip(args.fallback_mac.clone()) {
+        Some((target, mac)) => {
+            let fallback_args = ProxyArgs {
+                name: args.name.as_ref().map(|name| format!("{}-fallback", name)),
+                mac,
+                target: Some(target),
+                target_srv: None,
+                fallback_target: None,
+                fallback_mac: None,
+                // the wake history and circuit breaker are the primary's
+                state_file: None,
+                circuit_breaker_threshold: None,
+                // the primary's limiter is shared instead, below
+                wol_rate_limit: None,
+                ..args.clone()
+            };
+            let fallback =
+                build_proxy(fallback_args, ping, events.clone(), event_stream.clone()).await?;
+            Some(fallback)
+        }
+        None => None,
+    };
+    let mut proxy = build_proxy(args.clone(), ping, events, event_stream).await?;
+    if let Some(fallback) = &mut fallback {
+        fallback.share_limits(&proxy);
+    }
+    // puts the terminal back when this returns
+    #[cfg(all(feature = "tui", unix))]
+    let screen = match args.tui {
+        true => {
+            let (dashboard, screen) = Dashboard::start(format!("wol-proxy: {}", proxy.name()))?;
+            // progress lines would only clutter the log panel
+            proxy.progress = false;
+            if let Some(fallback) = &mut fallback {
+                fallback.progress = false;
+                fallback.dashboard = Some(dashboard.clone());
+            }
+            proxy.dashboard = Some(dashboard);
+            Some(screen)
+        }
+        false => None,
+    };
+    proxy.fallback = fallback.map(Arc::new);
+    let proxy = Arc::new(proxy);
+    #[cfg(all(feature = "tui", unix))]
+    if let Some(dashboard) = proxy.dashboard.clone() {
+        tokio::spawn(watch_target(proxy.clone(), dashboard));
+    }
+
+    if let Some(verify) = args.verify_mac {
+        verify_mac(&proxy, verify).await?;
+    }
+
+    if !args.monitor_interval.is_zero() {
+        tokio::spawn(monitor(proxy.clone(), args.monitor_interval));
+    }
+    if let Some(interval) = args.keepalive_wake.filter(|_| !args.dry_run) {
+        tokio::spawn(keepalive_wake(proxy.clone(), interval));
+    }
+    if let Some(schedule) = args.warm_schedule.filter(|_| !args.dry_run) {
+        tokio::spawn(warm_schedule(proxy.clone(), schedule, true));
+    }
+    for schedule in args.wake_schedule.into_iter().filter(|_| !args.dry_run) {
+        tokio::spawn(warm_schedule(proxy.clone(), schedule, args.schedule_wait));
+    }
+    if let Some(name) = args.target_srv.filter(|_| !args.srv_refresh.is_zero()) {
+        tokio::spawn(srv_refresh(
+            proxy.clone(),
+            srv::system_resolver()?,
+            name,
+            args.srv_refresh,
+            args.resolve_timeout,
+        ));
+    }
+
+    let listener = TcpListener::bind(&args.bind).await?;
+    if let Some((port, secret)) = args
+        .control_port
+        .zip(args.control_secret.clone())
+        .filter(|_| !args.dry_run)
+    {
+        let addr = SocketAddr::new(listener.local_addr()?.ip(), port);
+        let socket = UdpSocket::bind(addr).await?;
+        let verifier = control::Verifier::new(secret.into_bytes(), args.control_replay_window);
+        tokio::spawn(control_channel(proxy.clone(), socket, verifier));
+    }
+    privs::drop_privileges(privileges)?;
+    // checked as the user the pings are sent as
+    if pings {
+        check_ping_permission().await?;
+    }
+    // written as that user too, so it can be removed again on shutdown
+    let _pid_file = match args.pid_file {
+        Some(path) => {
+            let pid_file = PidFile::create(path)?;
+            if let Some(pid) = &pid_file.stale {
+                warn!("replaced a stale pid file (pid {})", pid);
+            }
+            Some(pid_file)
+        }
+        None => None,
+    };
+
+    // the pid file is removed when this returns, so stop cleanly on
+    // SIGINT/SIGTERM rather than being killed
+    let shutdown = shutdown::signal();
+    tokio::pin!(shutdown);
+    let mut next_id: u64 = 0;
+    let mut traffic = Traffic::default();
+    let started = Instant::now();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            result = &mut shutdown => {
+                // so the summary reaches the terminal
+                #[cfg(all(feature = "tui", unix))]
+                drop(screen);
+                info!("Shutting down...");
+                println!("{}", proxy.totals.summary(next_id, started.elapsed()));
+                let snapshot = proxy.history.lock().unwrap().snapshot(true);
+                if let Err(e) = tokio::task::spawn_blocking(|| snapshot.save()).await? {
+                    warn!("failed to save wake history: {}", e);
+                }
+                if let Some(path) = &args.learn {
+                    match std::fs::write(path, traffic.report()) {
+                        Ok(()) => info!("Wrote traffic report to {}", path.display()),
+                        Err(e) => warn!("failed to write {}: {}", path.display(), e),
+                    }
+                }
+                return result;
+            }
+        };
+        let peer = canonical_peer(peer);
+        let shown = show_client(peer, proxy.mask_client_ips);
+        if args.learn.is_some() {
+            traffic.record(Local::now());
+        }
+        if proxy.dry_run {
+            info!("Would send WoL to {}", proxy.wake_destination());
+            info!("Would proxy {} → {}", shown, proxy.target_addr());
+            // hold the connection open (discarding its data) so the client
+            // sees a realistic session
+            tokio::spawn(async move {
+                let mut stream = stream;
+                let _ = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await;
+            });
+            continue;
+        }
+        if proxy.refuses(next_id + 1, &shown) {
+            reset(stream);
+            continue;
+        }
+        if let Some(priority) = proxy.so_priority {
+            if let Err(e) = set_priority(&stream, priority) {
+                warn!("failed to set SO_PRIORITY for {}: {}", shown, e);
+            }
+        }
+        next_id += 1;
+        let client = Client { id: next_id, peer };
+        proxy.event(&client, Event::ConnectionAccepted);
+        let span = client.span(&shown);
+        let proxy = proxy.clone();
+        tokio::spawn(
+            async move {
+                let counts = Arc::new(ByteCounts::default());
+                let result = handle_connection(stream, &proxy, &client, &counts).await;
+                let outcome = outcome(&result);
+                if let Err(e) = result {
+                    error!("client handling error ({}): {}", shown, e);
+                    proxy.totals.errors.fetch_add(1, Ordering::SeqCst);
+                    proxy.event(
+                        &client,
+                        Event::ConnectionClosed {
+                            bytes_to_server: Some(counts.to_server()),
+                            bytes_to_client: Some(counts.to_client()),
+                            error: Some(e.to_string()),
+                        },
+                    );
+                }
+                match (&proxy.circuit_breaker, outcome) {
+                    (Some(breaker), Some(ok)) if breaker.record(client.id, ok) => warn!(
+                        "{} failed too often, refusing connections for {}",
+                        proxy.name(),
+                        humantime::format_duration(breaker.reset)
+                    ),
+                    (Some(breaker), None) => breaker.abandon(client.id),
+                    _ => {}
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let worker_threads = args.proxy.as_ref().and_then(|proxy| proxy.worker_threads);
+    runtime::build(worker_threads)?.block_on(run(args))
+}
+
+async fn run(args: Args) -> Result<()> {
+    match args.command {
+        Some(Command::Relay(relay_args)) => run_relay(relay_args).await,
+        Some(Command::SendWol(send_args)) => send_wol(send_args),
+        Some(Command::CheckTarget(check_args)) => check_target(check_args).await,
+        Some(Command::ListTargets(list_args)) => list_targets(*list_args).await,
+        Some(Command::GenerateCompletions(completions_args)) => {
+            cli::generate_completions(&mut Args::command(), &completions_args)
+        }
+        Some(Command::GenerateMan(man_args)) => cli::generate_man(
+            Args::command(),
+            &man_args,
+            &[
+                (
+                    "wol -m aa:bb:cc:dd:ee:ff -t 192.168.1.10:22 -b 0.0.0.0:2222",
+                    "Wake 192.168.1.10 when someone connects to port 2222 and forward the connection to its SSH port.",
+                ),
+                (
+                    "wol relay -b 0.0.0.0:9999 -k /etc/wol-proxy/relay.key",
+                    "Accept signed wake requests and broadcast the magic packet on the local network.",
+                ),
+                (
+                    "wol send-wol -m aa:bb:cc:dd:ee:ff -d 192.168.1.255 -r 3",
+                    "Send three magic packets to the LAN broadcast address and exit.",
+                ),
+            ],
+            &["keepawake"],
+        ),
+        None => {
+            let proxy = args.proxy.expect("clap requires the proxy arguments");
+            let log = args.log;
+            // the dashboard shows the log in a panel of its own
+            #[cfg(all(feature = "tui", unix))]
+            let log = if proxy.tui { log.without_color() } else { log };
+            log.init("wol")?;
+            run_proxy(proxy, &args.ping, &args.privileges).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use hickory_resolver::{
+        config::{ConnectionConfig, NameServerConfig, ResolverConfig},
+        net::runtime::TokioRuntimeProvider,
+        proto::{
+            op::Message,
+            rr::{
+                rdata::{A, SRV},
+                Name, RData, Record, RecordType,
+            },
+        },
+    };
+    use socket2::{Domain, Socket, Type};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::sync::atomic::AtomicU16;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UdpSocket,
+    };
+    use wol_proxy::mac::parse_arp_table;
+
+    fn local(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> chrono::DateTime<Local> {
+        Local.with_ymd_and_hms(y, mo, d, h, mi, 0).unwrap()
+    }
+
+    #[test]
+    fn warm_schedule_next_fire() {
+        let schedule = parse_schedule("0 30 7 * * Mon-Fri").unwrap();
+        // Wednesday morning, before and after the time
+        let next = |at| schedule.after(&at).next().unwrap();
+        assert_eq!(next(local(2026, 10, 14, 6, 0)), local(2026, 10, 14, 7, 30));
+        assert_eq!(next(local(2026, 10, 14, 8, 0)), local(2026, 10, 15, 7, 30));
+        // Friday after the time skips the weekend
+        assert_eq!(next(local(2026, 10, 16, 8, 0)), local(2026, 10, 19, 7, 30));
+        assert!(parse_schedule("30 7 * * *").is_err());
+    }
+
+    #[test]
+    fn wake_schedule_next_fire() {
+        let schedule = parse_daily_time("07:30").unwrap();
+        let next = |at| schedule.after(&at).next().unwrap();
+        assert_eq!(next(local(2026, 10, 14, 6, 0)), local(2026, 10, 14, 7, 30));
+        assert_eq!(next(local(2026, 10, 17, 8, 0)), local(2026, 10, 18, 7, 30));
+        assert!(parse_daily_time("7.30").is_err());
+    }
+
+    #[test]
+    fn version_names_the_package() {
+        let version = Args::command().render_version();
+        assert!(version.starts_with(concat!("wol-proxy ", env!("CARGO_PKG_VERSION"), " (")));
+    }
+
+    #[test]
+    fn relay_mode_flags() {
+        for argv in [
+            ["wol", "relay", "-b", "0.0.0.0:9999", "-k", "relay.key"],
+            [
+                "wol",
+                "--relay-mode",
+                "--relay-listen",
+                "0.0.0.0:9999",
+                "-k",
+                "relay.key",
+            ],
+        ] {
+            let Some(Command::Relay(args)) = Args::try_parse_from(argv).unwrap().command else {
+                panic!("not relay mode");
+            };
+            assert_eq!(args.bind, "0.0.0.0:9999");
+            assert_eq!(args.key_file, "relay.key");
+        }
+    }
+
+    #[tokio::test]
+    async fn require_unicast_mac_stands_alone() {
+        let build = |flag| async move {
+            let args = Args::try_parse_from([
+                "wol",
+                "-m",
+                "ff:ff:ff:ff:ff:ff",
+                "-t",
+                "127.0.0.1:9",
+                "-b",
+                "127.0.0.1:0",
+                flag,
+            ]);
+            let args = args.unwrap();
+            build_proxy(args.proxy.unwrap(), &args.ping, None, None).await
+        };
+        assert!(build("--require-unicast-mac").await.is_err());
+        // --strict-mac only checks the server's ARP entry
+        assert!(build("--strict-mac").await.is_ok());
+    }
+
+    #[test]
+    fn confirm_count_must_be_positive() {
+        let args = |count| {
+            Args::try_parse_from([
+                "wol",
+                "-m",
+                "52:54:00:12:34:56",
+                "-t",
+                "127.0.0.1:9",
+                "-b",
+                "127.0.0.1:0",
+                "--confirm-count",
+                count,
+            ])
+        };
+        assert!(args("0").is_err());
+        assert_eq!(args("2").unwrap().proxy.unwrap().confirm_count, 2);
+    }
+
+    #[test]
+    fn wake_anomaly_threshold_must_be_positive() {
+        let args = |threshold| {
+            Args::try_parse_from([
+                "wol",
+                "-m",
+                "52:54:00:12:34:56",
+                "-t",
+                "127.0.0.1:9",
+                "-b",
+                "127.0.0.1:0",
+                "--wake-anomaly-threshold",
+                threshold,
+            ])
+        };
+        for bad in ["0", "-1", "nan", "inf", "lots"] {
+            assert!(args(bad).is_err(), "{bad}");
+        }
+        let threshold = args("1.5").unwrap().proxy.unwrap().wake_anomaly_threshold;
+        assert_eq!(threshold, 1.5);
+    }
+
+    #[test]
+    fn slow_wakes() {
+        let secs = Duration::from_secs;
+        assert!(is_slow_wake(secs(21), secs(10), 2.0));
+        assert!(!is_slow_wake(secs(20), secs(10), 2.0));
+        // a limit past what a Duration holds is never reached
+        assert!(!is_slow_wake(secs(u64::MAX), secs(10), 1e300));
+    }
+
+    #[test]
+    fn keepalive_wake_must_be_positive() {
+        let args = |interval| {
+            Args::try_parse_from([
+                "wol",
+                "-m",
+                "52:54:00:12:34:56",
+                "-t",
+                "127.0.0.1:9",
+                "-b",
+                "127.0.0.1:0",
+                "--keepalive-wake",
+                interval,
+            ])
+        };
+        assert!(args("0").is_err());
+        let interval = args("30s").unwrap().proxy.unwrap().keepalive_wake;
+        assert_eq!(interval, Some(Duration::from_secs(30)));
+    }
+
+    /// The arguments for a proxy to `target`, with `extra` options on top.
+    fn proxy_args(target: &str, extra: &[&str]) -> Args {
+        let mut args = vec![
+            "wol",
+            "-m",
+            "52:54:00:12:34:56",
+            "-t",
+            target,
+            "-b",
+            "127.0.0.1:0",
+        ];
+        args.extend_from_slice(extra);
+        Args::try_parse_from(args).unwrap()
+    }
+
+    async fn proxy(target: &str, extra: &[&str]) -> Proxy {
+        let args = proxy_args(target, extra);
+        build_proxy(args.proxy.unwrap(), &args.ping, None, None)
+            .await
+            .unwrap()
+    }
+
+    /// Run the whole proxy for `target` with `extra` options, and connect
+    /// to it once it is listening.
+    async fn run(target: &str, extra: &[&str]) -> TcpStream {
+        // a free port, so the test knows where the proxy listens
+        let bind = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let mut argv = vec!["wol", "-m", "52:54:00:12:34:56", "-t", target, "-b", &bind];
+        argv.extend_from_slice(extra);
+        let args = Args::try_parse_from(argv).unwrap();
+        tokio::spawn(
+            async move { run_proxy(args.proxy.unwrap(), &args.ping, &args.privileges).await },
+        );
+        loop {
+            if let Ok(stream) = TcpStream::connect(&bind).await {
+                return stream;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// The SRV record `srv_server` answers for.
+    const SRV_NAME: &str = "_ssh._tcp.test.";
+
+    /// Start a name server answering `SRV_NAME` with `host.test.` on the
+    /// port in `port`, and `host.test.` with 127.0.0.1, and return a
+    /// resolver that asks it.
+    async fn srv_server(port: Arc<AtomicU16>) -> TokioResolver {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let query = Message::from_vec(&buf[..len]).unwrap();
+                let mut reply = Message::response(query.metadata.id, query.metadata.op_code);
+                for question in query.queries {
+                    let data = match question.query_type() {
+                        RecordType::SRV => {
+                            let host = Name::from_ascii("host.test.").unwrap();
+                            Some(RData::SRV(SRV::new(
+                                10,
+                                0,
+                                port.load(Ordering::SeqCst),
+                                host,
+                            )))
+                        }
+                        RecordType::A => Some(RData::A(A(Ipv4Addr::LOCALHOST))),
+                        _ => None,
+                    };
+                    if let Some(data) = data {
+                        // not cached, so each lookup sees the current port
+                        reply.add_answer(Record::from_rdata(question.name().clone(), 0, data));
+                    }
+                    reply.add_query(question);
+                }
+                socket
+                    .send_to(&reply.to_vec().unwrap(), from)
+                    .await
+                    .unwrap();
+            }
+        });
+        let mut udp = ConnectionConfig::udp();
+        udp.port = addr.port();
+        let server = NameServerConfig::new(addr.ip(), true, vec![udp]);
+        let config = ResolverConfig::from_name_servers(vec![server]);
+        TokioResolver::builder_with_config(config, TokioRuntimeProvider::default())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn srv_record_is_followed() {
+        let port = Arc::new(AtomicU16::new(2222));
+        let resolver = srv_server(port.clone()).await;
+        let timeout = Duration::from_secs(5);
+        let addrs = resolve_srv(&resolver, SRV_NAME, timeout).await.unwrap();
+        let first = SocketAddr::from((Ipv4Addr::LOCALHOST, 2222));
+        assert_eq!(addrs, [first]);
+
+        // the service moves, and the next lookup follows it
+        let proxy = Arc::new(proxy(&first.to_string(), &[]).await);
+        let interval = Duration::from_millis(50);
+        let name = SRV_NAME.to_string();
+        tokio::spawn(srv_refresh(
+            proxy.clone(),
+            resolver,
+            name,
+            interval,
+            timeout,
+        ));
+        port.store(2223, Ordering::SeqCst);
+        let moved = SocketAddr::from((Ipv4Addr::LOCALHOST, 2223));
+        let followed = async {
+            while proxy.target_addr() != moved {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(timeout, followed).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn long_pings_skip_new_ipv6_addresses() {
+        let v4 = SocketAddr::from((Ipv4Addr::LOCALHOST, 22));
+        let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, 22));
+        let short = proxy(&v4.to_string(), &[]).await;
+        assert_eq!(short.pingable("host.test", vec![v6, v4]), [v6, v4]);
+        let payload = "ab".repeat(MAX_PING_PAYLOAD_V6 + 1);
+        let long = proxy(&v4.to_string(), &["--ping-payload", &payload]).await;
+        assert_eq!(long.pingable("host.test", vec![v6, v4]), [v4]);
+        assert!(long.pingable("host.test", vec![v6]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn relay_checks_the_ping_payload() {
+        let payload = "ab".repeat(MAX_PING_PAYLOAD_V6 + 1);
+        let argv = ["wol", "relay", "-b", "0.0.0.0:9999", "-k", "relay.key"];
+        let args = Args::try_parse_from(argv.iter().chain(&["--ping-payload", &payload]));
+        let Some(Command::Relay(args)) = args.unwrap().command else {
+            panic!("not relay mode");
+        };
+        let settings = ping_settings(&args.ping);
+        let req = WakeRequest {
+            mac: "52:54:00:12:34:56".to_string(),
+            timestamp: relay::unix_time(),
+            nonce: "1".to_string(),
+            port: None,
+            target: Some(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+            confirm: true,
+        };
+        let broadcast = SocketAddr::from((Ipv4Addr::LOCALHOST, 9));
+        let timeout = Duration::from_secs(1);
+        let error = relay_wake(&req, &broadcast, timeout, &settings)
+            .await
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("the most for an IPv6 target is 1452"));
+    }
+
+    #[tokio::test]
+    async fn wake_cap_window_slides() {
+        let proxy = proxy("127.0.0.1:9", &["--max-wakes-per-hour", "2"]).await;
+        let start = Instant::now();
+        let at = |minutes: u64| start + Duration::from_secs(minutes * 60);
+        proxy.record_wake_at(at(0)).unwrap();
+        proxy.record_wake_at(at(30)).unwrap();
+        // a third within the hour is refused, and not counted, nor held
+        // against the server by --circuit-breaker
+        let refused = proxy.record_wake_at(at(59)).map(|()| Served::Proxied);
+        assert!(refused.as_ref().unwrap_err().is::<Declined>());
+        assert_eq!(outcome(&refused), None);
+        // an hour after the first wake, there is room for one more
+        proxy.record_wake_at(at(60)).unwrap();
+        assert!(proxy.record_wake_at(at(89)).is_err());
+        proxy.record_wake_at(at(90)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rate_limiter_queues_and_drops() {
+        let limiter = WolRateLimiter::start(60, Some(1));
+        // the bucket starts full
+        for _ in 0..60 {
+            assert!(limiter.acquire().await);
+        }
+        let waiting = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire().await }
+        });
+        tokio::task::yield_now().await;
+        // a second send queueing up pushes the first out of the full queue,
+        // and gets the next token
+        let start = Instant::now();
+        assert!(limiter.acquire().await);
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert!(!waiting.await.unwrap());
+    }
+
+    /// Answer one SOCKS5 CONNECT on `listener`, checking that it asks for
+    /// `target` and logs in with `auth`, then echo back what is sent.
+    #[cfg(feature = "socks5")]
+    async fn socks5_server(
+        listener: TcpListener,
+        target: SocketAddr,
+        auth: Option<(&'static str, &'static str)>,
+    ) {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut greeting = [0u8; 2];
+        conn.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(greeting[0], 5);
+        let mut methods = vec![0u8; greeting[1] as usize];
+        conn.read_exact(&mut methods).await.unwrap();
+        match auth {
+            Some((user, password)) => {
+                assert!(methods.contains(&2));
+                conn.write_all(&[5, 2]).await.unwrap();
+                let mut head = [0u8; 2];
+                conn.read_exact(&mut head).await.unwrap();
+                assert_eq!(head[0], 1);
+                let mut got_user = vec![0u8; head[1] as usize];
+                conn.read_exact(&mut got_user).await.unwrap();
+                let len = conn.read_u8().await.unwrap();
+                let mut got_password = vec![0u8; len as usize];
+                conn.read_exact(&mut got_password).await.unwrap();
+                assert_eq!(got_user, user.as_bytes());
+                assert_eq!(got_password, password.as_bytes());
+                conn.write_all(&[1, 0]).await.unwrap();
+            }
+            None => {
+                assert!(methods.contains(&0));
+                conn.write_all(&[5, 0]).await.unwrap();
+            }
+        }
+        let IpAddr::V4(ip) = target.ip() else {
+            panic!("only IPv4 targets are checked");
+        };
+        let mut request = [0u8; 10];
+        conn.read_exact(&mut request).await.unwrap();
+        assert_eq!(request[..4], [5, 1, 0, 1]);
+        assert_eq!(request[4..8], ip.octets());
+        assert_eq!(request[8..], target.port().to_be_bytes());
+        conn.write_all(&[5, 0, 0, 1, 127, 0, 0, 1, 0, 0])
+            .await
+            .unwrap();
+        let (mut from, mut to) = conn.split();
+        tokio::io::copy(&mut from, &mut to).await.unwrap();
+    }
+
+    #[cfg(feature = "socks5")]
+    #[tokio::test]
+    async fn connects_through_socks5() {
+        // only reachable through the proxy
+        let target = "192.0.2.10:22";
+        for auth in [None, Some(("user", "secret"))] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let socks5 = listener.local_addr().unwrap().to_string();
+            let mut extra = vec!["--socks5", &socks5];
+            if let Some((user, password)) = auth {
+                extra.extend(["--socks5-user", user, "--socks5-password", password]);
+            }
+            let proxy = proxy(target, &extra).await;
+            let server = tokio::spawn(socks5_server(listener, target.parse().unwrap(), auth));
+
+            let mut conn = proxy.connect().await.unwrap();
+            conn.write_all(b"ping").await.unwrap();
+            let mut buf = [0u8; 4];
+            conn.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            drop(conn);
+            server.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn wait_online_needs_replies_in_a_row() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let proxy = proxy(&target.to_string(), &["--wake-detect", "tcp"]).await;
+        let steady = Arc::new(AtomicUsize::new(0));
+        let replies = steady.clone();
+        tokio::spawn(async move {
+            // answer the first probe, miss the next, then answer steadily
+            listener.accept().await.unwrap();
+            drop(listener);
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            let listener = TcpListener::bind(target).await.unwrap();
+            loop {
+                listener.accept().await.unwrap();
+                replies.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        assert!(proxy.wait_online(Duration::from_secs(10), 3, false).await);
+        // let the last connection be accepted
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // the first reply was forgotten once a probe went unanswered
+        assert_eq!(steady.load(Ordering::SeqCst), 3);
+    }
+
+    /// Echo back what each connection to `listener` sends.
+    async fn echo(listener: TcpListener) {
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut from, mut to) = conn.split();
+                let _ = tokio::io::copy(&mut from, &mut to).await;
+            });
+        }
+    }
+
+    /// The address of a server that is up, echoing back what it is sent.
+    async fn awake() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(echo(listener));
+        addr
+    }
+
+    /// The path of a file in tests/data.
+    #[cfg(feature = "tls")]
+    fn test_data(name: &str) -> String {
+        format!("{}/tests/data/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// Connect to `addr` over TLS, as in `tls_handshake`.
+    #[cfg(feature = "tls")]
+    async fn tls_connect(
+        addr: SocketAddr,
+        cert: Option<&str>,
+    ) -> std::io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        tls_handshake(TcpStream::connect(addr).await?, cert).await
+    }
+
+    /// Start TLS on `conn`, trusting the test CA, and presenting the client
+    /// certificate in tests/data called `cert` if there is one.
+    #[cfg(feature = "tls")]
+    async fn tls_handshake(
+        conn: TcpStream,
+        cert: Option<&str>,
+    ) -> std::io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        use tokio_rustls::rustls::{
+            pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName},
+            ClientConfig, RootCertStore,
+        };
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(test_data("ca.pem")).unwrap())
+            .unwrap();
+        let config = ClientConfig::builder().with_root_certificates(roots);
+        let config = match cert {
+            Some(name) => config
+                .with_client_auth_cert(
+                    vec![
+                        CertificateDer::from_pem_file(test_data(&format!("{}.pem", name))).unwrap(),
+                    ],
+                    PrivateKeyDer::from_pem_file(test_data(&format!("{}.key", name))).unwrap(),
+                )
+                .unwrap(),
+            None => config.with_no_client_auth(),
+        };
+        let name = ServerName::try_from("localhost").unwrap();
+        tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(name, conn)
+            .await
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn tls_is_terminated() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (cert, key) = (test_data("server.pem"), test_data("server.key"));
+        let extra = ["--no-wake", "--tls-cert", &cert, "--tls-key", &key];
+        let proxy = Arc::new(proxy(&backend.local_addr().unwrap().to_string(), &extra).await);
+        let front = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = front.local_addr().unwrap();
+
+        let talk = async {
+            let mut conn = tls_connect(addr, None).await.unwrap();
+            conn.write_all(b"hello").await.unwrap();
+            let mut reply = [0u8; 5];
+            conn.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply, b"world");
+            conn.shutdown().await.unwrap();
+        };
+        // the server is sent the plaintext
+        let server = async {
+            let (mut conn, _) = backend.accept().await.unwrap();
+            let mut request = [0u8; 5];
+            conn.read_exact(&mut request).await.unwrap();
+            assert_eq!(&request, b"hello");
+            conn.write_all(b"world").await.unwrap();
+        };
+        let serve = async {
+            let (stream, _) = front.accept().await.unwrap();
+            handle_connection(stream, &proxy, &client(), &Arc::default()).await
+        };
+        let (served, (), ()) = tokio::join!(serve, talk, server);
+        served.unwrap();
+    }
+
+    /// The test proxies' --mac.
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    /// Catch the magic packets sent for a server at `target`, which go to
+    /// the same port over UDP.
+    async fn magic_packets(target: &str) -> UdpSocket {
+        UdpSocket::bind(target).await.unwrap()
+    }
+
+    /// Whether a magic packet for `MAC` arrives within `within`.
+    async fn magic_packet_within(packets: &UdpSocket, within: Duration) -> bool {
+        let mut buf = [0u8; 256];
+        match tokio::time::timeout(within, packets.recv(&mut buf)).await {
+            Ok(received) => {
+                let len = received.unwrap();
+                let expected = wake_on_lan::MagicPacket::new(&MAC);
+                assert_eq!(&buf[..len], expected.magic_bytes());
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn client_certificates_are_required() {
+        let (cert, key, ca) = (
+            test_data("server.pem"),
+            test_data("server.key"),
+            test_data("ca.pem"),
+        );
+        let tls = ["--tls-cert", &cert, "--tls-key", &key, "--client-ca", &ca];
+        for (client_cert, allowed) in [
+            (Some("client"), true),
+            (None, false),
+            (Some("untrusted"), false),
+        ] {
+            let target = match allowed {
+                true => awake().await,
+                false => asleep().await,
+            };
+            let packets = magic_packets(&target).await;
+            let mut extra = vec!["--wake-detect", "tcp"];
+            extra.extend(tls);
+            let proxy = Arc::new(proxy(&target, &extra).await);
+            let front = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = front.local_addr().unwrap();
+
+            let talk = async {
+                let Ok(mut conn) = tls_connect(addr, client_cert).await else {
+                    return;
+                };
+                let mut reply = [0u8; 4];
+                if allowed {
+                    conn.write_all(b"ping").await.unwrap();
+                    conn.read_exact(&mut reply).await.unwrap();
+                    assert_eq!(&reply, b"ping");
+                    conn.shutdown().await.unwrap();
+                } else {
+                    // the server rejects the certificate once the client
+                    // has sent it
+                    let _ = conn.write_all(b"ping").await;
+                    let _ = conn.read(&mut reply).await;
+                }
+            };
+            let serve = async {
+                let (stream, _) = front.accept().await.unwrap();
+                handle_connection(stream, &proxy, &client(), &Arc::default()).await
+            };
+            let (served, ()) = tokio::join!(serve, talk);
+            assert_eq!(served.is_ok(), allowed, "{:?}", client_cert);
+            if !allowed {
+                // turned away before the server was woken
+                assert!(!magic_packet_within(&packets, Duration::from_millis(100)).await);
+                assert_eq!(proxy.totals.wakes_sent.load(Ordering::SeqCst), 0);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn keepalive_wakes_a_server_that_stops_answering() {
+        let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = server.local_addr().unwrap().to_string();
+        let packets = magic_packets(&target).await;
+        let proxy = Arc::new(proxy(&target, &["--wake-detect", "tcp"]).await);
+        proxy.active_connections.store(1, Ordering::SeqCst);
+        tokio::spawn(keepalive_wake(proxy.clone(), Duration::from_millis(100)));
+        // nothing is sent while the server answers
+        assert!(!magic_packet_within(&packets, Duration::from_millis(500)).await);
+
+        // it goes to sleep in the middle of the session
+        drop(server);
+        assert!(magic_packet_within(&packets, Duration::from_secs(5)).await);
+
+        // and without an open connection it is left to sleep
+        proxy.active_connections.store(0, Ordering::SeqCst);
+        while magic_packet_within(&packets, Duration::from_millis(200)).await {}
+        assert!(!magic_packet_within(&packets, Duration::from_millis(500)).await);
+    }
+
+    #[tokio::test]
+    async fn each_stage_times_out_with_its_own_error() {
+        // a name server that never answers
+        let lookup = std::future::pending::<std::io::Result<std::vec::IntoIter<SocketAddr>>>();
+        let error = resolve_within("server:22", Duration::from_millis(100), lookup)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "timed out resolving server:22 after 100ms"
+        );
+
+        let extra = ["--wake-detect", "tcp", "--timeout", "200ms"];
+        let asleep = Arc::new(proxy(&asleep().await, &extra).await);
+        let (stream, _peer) = tokio::io::duplex(64);
+        let error = serve(stream, &asleep, &client(), &Arc::default(), Vec::new())
+            .await
+            .unwrap_err();
+        assert_eq!(error.to_string(), "Server did not wake up in time");
+
+        // a server whose accept queue is full leaves connections hanging
+        let listener = Socket::new(Domain::IPV4, Type::STREAM, None).unwrap();
+        listener
+            .bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into())
+            .unwrap();
+        listener.listen(0).unwrap();
+        let addr = listener.local_addr().unwrap().as_socket().unwrap();
+        let mut queued = Vec::new();
+        while let Ok(conn) =
+            tokio::time::timeout(Duration::from_millis(200), TcpStream::connect(addr)).await
+        {
+            queued.push(conn.unwrap());
+        }
+        let proxy = proxy(&addr.to_string(), &["--connect-timeout", "200ms"]).await;
+        let error = proxy.connect().await.unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with(&format!("timed out connecting to {} after 200ms", addr)));
+    }
+
+    #[tokio::test]
+    async fn reconnects_when_the_server_drops_the_connection() {
+        // reset, then closed cleanly
+        for abortive in [true, false] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let target = listener.local_addr().unwrap();
+            let extra = ["--no-wake", "--reconnect-backend", "--connect-retry", "0"];
+            let proxy = Arc::new(proxy(&target.to_string(), &extra).await);
+            tokio::spawn(async move {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 3];
+                conn.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"one");
+                // the service restarts, dropping the connection
+                drop(listener);
+                let listener = TcpListener::bind(target).await.unwrap();
+                if abortive {
+                    reset(conn);
+                } else {
+                    drop(conn);
+                }
+                echo(listener).await;
+            });
+
+            let (stream, mut peer) = tokio::io::duplex(64);
+            let talk = async move {
+                peer.write_all(b"one").await.unwrap();
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                // carried on over the new connection
+                peer.write_all(b"two").await.unwrap();
+                let mut buf = [0u8; 3];
+                peer.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"two");
+            };
+            let serving =
+                async { serve(stream, &proxy, &client(), &Arc::default(), Vec::new()).await };
+            let (served, ()) = tokio::join!(serving, talk);
+            assert_eq!(served.unwrap(), Served::Proxied);
+        }
+    }
+
+    #[tokio::test]
+    async fn auto_reconnect_wakes_the_server_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let packets = magic_packets(&target.to_string()).await;
+        let extra = [
+            "--wake-detect",
+            "tcp",
+            "--auto-reconnect",
+            "--connect-retry",
+            "0",
+        ];
+        let proxy = Arc::new(proxy(&target.to_string(), &extra).await);
+        tokio::spawn(async move {
+            // past the proxy's checks that it is up, to the client's
+            // connection
+            let conn = loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 3];
+                if conn.read_exact(&mut buf).await.is_ok() {
+                    assert_eq!(&buf, b"one");
+                    break conn;
+                }
+            };
+            // the server goes to sleep early in the session
+            drop(listener);
+            reset(conn);
+            // until the proxy wakes it again
+            assert!(magic_packet_within(&packets, Duration::from_secs(10)).await);
+            echo(TcpListener::bind(target).await.unwrap()).await;
+        });
+
+        let (stream, mut peer) = tokio::io::duplex(64);
+        let talk = async move {
+            peer.write_all(b"one").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            // carried on over the new connection
+            peer.write_all(b"two").await.unwrap();
+            let mut buf = [0u8; 3];
+            peer.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"two");
+        };
+        let serving = async { serve(stream, &proxy, &client(), &Arc::default(), Vec::new()).await };
+        let (served, ()) = tokio::join!(serving, talk);
+        assert_eq!(served.unwrap(), Served::Proxied);
+        // it was up to begin with, so that was the only wake
+        assert_eq!(proxy.totals.wakes_sent.load(Ordering::SeqCst), 1);
+        assert_eq!(proxy.totals.wakes_confirmed.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn linger_closes_gracefully() {
+        let linger = |linger| {
+            Args::try_parse_from([
+                "wol",
+                "-m",
+                "52:54:00:12:34:56",
+                "-t",
+                "127.0.0.1:9",
+                "-b",
+                "127.0.0.1:0",
+                "--linger",
+                linger,
+            ])
+        };
+        // cut down to 0, that would reset the connection instead
+        assert!(linger("500ms").is_err());
+        assert!(linger("1.5s").is_err());
+        let two = linger("2s").unwrap().proxy.unwrap().linger;
+        assert_eq!(two, Duration::from_secs(2));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conn = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        close_lingering(conn, two, "the server".to_string());
+        // a FIN, where an abortive close would reset the connection
+        assert_eq!(server.read(&mut [0u8; 16]).await.unwrap(), 0);
+    }
+
+    /// An address nothing listens on, standing in for a sleeping server.
+    async fn asleep() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    /// When a client handled with the default --handshake-timeout would
+    /// have to send its auth token by.
+    fn deadline() -> tokio::time::Instant {
+        tokio::time::Instant::now() + Duration::from_secs(5)
+    }
+
+    fn client() -> Client {
+        Client {
+            id: 1,
+            peer: SocketAddr::from(([192, 0, 2, 1], 1000)),
+        }
+    }
+
+    #[tokio::test]
+    async fn no_wake_sends_no_packet() {
+        let target = asleep().await;
+        let packets = magic_packets(&target).await;
+        let extra = ["--wake-detect", "tcp", "--no-wake"];
+        let proxy = Arc::new(proxy(&target, &extra).await);
+        // straight on to connecting, which fails with the server asleep
+        let (stream, _peer) = tokio::io::duplex(64);
+        assert!(
+            handle_client(stream, &proxy, &client(), &Arc::default(), deadline())
+                .await
+                .is_err()
+        );
+        assert!(!magic_packet_within(&packets, Duration::from_millis(500)).await);
+        assert_eq!(proxy.totals.wakes_sent.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn dry_run_holds_connections_open() {
+        let target = asleep().await;
+        let packets = magic_packets(&target).await;
+        let mut stream = run(&target, &["--wake-detect", "tcp", "--dry-run"]).await;
+        stream.write_all(b"hello").await.unwrap();
+        // neither forwarded nor closed
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_millis(200), stream.read(&mut buf));
+        assert!(read.await.is_err());
+        assert!(!magic_packet_within(&packets, Duration::from_millis(200)).await);
+    }
+
+    #[tokio::test]
+    async fn ping_answered_over_either_family() {
+        let v4 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        // a half-booted dual-stack server that only answers over IPv6
+        let half_booted = |ip: IpAddr| {
+            Box::pin(async move {
+                match ip {
+                    IpAddr::V4(_) => {
+                        tokio::time::sleep(Duration::from_secs(10)).await;
+                        Err(())
+                    }
+                    IpAddr::V6(_) => Ok(()),
+                }
+            })
+        };
+        let start = Instant::now();
+        assert!(any_succeeds([v4, v6].map(half_booted)).await);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        let asleep = |_| std::future::ready(Err::<(), _>(()));
+        assert!(!any_succeeds([v4, v6].map(asleep)).await);
+        assert!(!any_succeeds(Vec::<std::future::Ready<Result<(), ()>>>::new()).await);
+    }
+
+    #[tokio::test]
+    async fn ping_races_both_families() {
+        // real pings need CAP_NET_RAW or unprivileged pings allowed
+        if check_ping_permission().await.is_err() {
+            return;
+        }
+        let settings = PingSettings {
+            options: PingOptions {
+                ttl: 64,
+                dont_fragment: false,
+            },
+            payload: vec![0xde, 0xad, 0xbe, 0xef],
+        };
+        // nothing should answer on TEST-NET-1, but the IPv6 loopback
+        // answering straight away is enough
+        let v4 = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let v6 = IpAddr::V6(Ipv6Addr::LOCALHOST);
+        let start = Instant::now();
+        assert!(ping(&[v4, v6], &settings).await);
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(!ping(&[], &settings).await);
+    }
+
+    /// Stand in for a server at `target` that is asleep until it is sent a
+    /// magic packet, then takes `boot` to come up, echoing back what it is
+    /// sent.
+    async fn sleeper(target: &str, boot: Duration) {
+        let packets = magic_packets(target).await;
+        tokio::spawn(async move {
+            assert!(magic_packet_within(&packets, Duration::from_secs(60)).await);
+            tokio::time::sleep(boot).await;
+            let target = packets.local_addr().unwrap();
+            echo(TcpListener::bind(target).await.unwrap()).await;
+        });
+    }
+
+    /// Proxy a client that sends `ping`, checking that it comes back.
+    async fn round_trip(proxy: &Arc<Proxy>) -> Result<Served> {
+        let (stream, mut peer) = tokio::io::duplex(64);
+        let talk = async move {
+            peer.write_all(b"ping").await.unwrap();
+            let mut reply = [0u8; 4];
+            peer.read_exact(&mut reply).await.unwrap();
+            assert_eq!(&reply, b"ping");
+        };
+        let serving = async { serve(stream, proxy, &client(), &Arc::default(), Vec::new()).await };
+        let (served, ()) = tokio::join!(serving, talk);
+        served
+    }
+
+    #[tokio::test]
+    async fn progress_lines_while_waking() {
+        let target = asleep().await;
+        sleeper(&target, Duration::from_millis(6500)).await;
+        let extra = ["--wake-detect", "tcp", "--progress-protocol"];
+        let proxy = Arc::new(proxy(&target, &extra).await);
+        let (stream, peer) = tokio::io::duplex(64);
+        let start = Instant::now();
+        let talk = async move {
+            use tokio::io::AsyncBufReadExt;
+            let mut lines = tokio::io::BufReader::new(peer).lines();
+            let mut seen = Vec::new();
+            while let Some(line) = lines.next_line().await.unwrap() {
+                seen.push((line.clone(), start.elapsed()));
+                if line == "ready" {
+                    break;
+                }
+            }
+            seen
+        };
+        let serving =
+            async { handle_client(stream, &proxy, &client(), &Arc::default(), deadline()).await };
+        let (served, seen) = tokio::join!(serving, talk);
+        served.unwrap();
+        let lines: Vec<_> = seen.iter().map(|(line, _)| line.as_str()).collect();
+        assert_eq!(lines, ["waking... 3s", "waking... 6s", "ready"]);
+        // each written as its interval came up, not all at the end
+        assert!(seen[0].1 >= PROGRESS_INTERVAL && seen[0].1 < 2 * PROGRESS_INTERVAL);
+        assert!(seen[1].1 >= 2 * PROGRESS_INTERVAL && seen[1].1 < seen[2].1);
+    }
+
+    #[tokio::test]
+    async fn connect_retries_until_the_service_starts() {
+        let target = asleep().await;
+        // --timeout is only for waking, and doesn't cut the retries short
+        let extra = [
+            "--connect-retry",
+            "3",
+            "--connect-retry-delay",
+            "100ms",
+            "--timeout",
+            "100ms",
+        ];
+        let starting = proxy(&target, &extra).await;
+        // the service starts a while after the server came up
+        let listen = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            let listener = TcpListener::bind(target).await.unwrap();
+            listener.accept().await.unwrap()
+        });
+        starting.connect_retrying(Instant::now()).await.unwrap();
+        listen.await.unwrap();
+
+        // a service that never starts is given --connect-retry retries
+        let extra = ["--connect-retry", "2", "--connect-retry-delay", "100ms"];
+        let never = proxy(&asleep().await, &extra).await;
+        let up_since = Instant::now();
+        assert!(never.connect_retrying(up_since).await.is_err());
+        let elapsed = up_since.elapsed();
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn service_ready_timeout_outlasts_connect_retry() {
+        let target = asleep().await;
+        let extra = [
+            "--connect-retry",
+            "0",
+            "--connect-retry-delay",
+            "100ms",
+            "--service-ready-timeout",
+            "5s",
+        ];
+        let proxy = proxy(&target, &extra).await;
+        // the service starts a while after the server came up
+        let listen = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let listener = TcpListener::bind(target).await.unwrap();
+            listener.accept().await.unwrap()
+        });
+        let up_since = Instant::now();
+        proxy.connect_retrying(up_since).await.unwrap();
+        listen.await.unwrap();
+        assert!(up_since.elapsed() >= Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn cooldown_holds_back_repeat_packets() {
+        let extra = ["--wol-cooldown", "1m", "--max-wakes-per-hour", "5"];
+        let proxy = proxy("127.0.0.1:9", &extra).await;
+        assert!(proxy.wake_once().await.unwrap());
+        assert!(!proxy.wake_once().await.unwrap());
+        // only the packet that went out counts
+        assert_eq!(proxy.totals.wakes_sent.load(Ordering::SeqCst), 1);
+        assert_eq!(proxy.wake_times.lock().unwrap().len(), 1);
+        // other methods aren't held back by it, and a failed send (there is
+        // no --relay-to) doesn't hold back the next try either
+        for _ in 0..2 {
+            assert!(proxy.send_wake(WakeMethod::Relay).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_banner_comes_first() {
+        // a server that greets each connection
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
         tokio::spawn(async move {
-            match handle_client(stream, &target_addr.into(), &mac, args.timeout).await {
-                Ok(_) => {}
-                Err(e) => eprintln!("client handling error: {}", e),
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let _ = conn.write_all(b"SSH-2.0-test\r\n").await;
+            }
+        });
+        let extra = [
+            "--wake-detect",
+            "tcp",
+            "--connect-banner",
+            "Waking homelab...",
+        ];
+        let proxy = Arc::new(proxy(&target, &extra).await);
+        let (stream, mut peer) = tokio::io::duplex(64);
+        let talk = async move {
+            let mut received = Vec::new();
+            peer.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let handling =
+            async { handle_client(stream, &proxy, &client(), &Arc::default(), deadline()).await };
+        let (handled, received) = tokio::join!(handling, talk);
+        handled.unwrap();
+        assert_eq!(received, b"Waking homelab...\r\nSSH-2.0-test\r\n");
+    }
+
+    #[tokio::test]
+    async fn verify_mac_compares_the_arp_entry() {
+        let target = awake().await;
+        let proxy = proxy(&target, &["--wake-detect", "tcp"]).await;
+        let table = |mac| {
+            format!(
+                "IP address       HW type     Flags       HW address            Mask     Device\n\
+                 127.0.0.1        0x1         0x2         {}     *        lo\n",
+                mac
+            )
+        };
+        let matching = table("52:54:00:12:34:56");
+        let wrong = table("52:54:00:65:43:21");
+        let arp = |table: &str| {
+            let entry = parse_arp_table(table, Ipv4Addr::LOCALHOST);
+            move |_| Ok(entry)
+        };
+        for verify in [VerifyMac::Warn, VerifyMac::Fail] {
+            verify_mac_with(&proxy, verify, arp(&matching))
+                .await
+                .unwrap();
+        }
+        verify_mac_with(&proxy, VerifyMac::Warn, arp(&wrong))
+            .await
+            .unwrap();
+        let error = verify_mac_with(&proxy, VerifyMac::Fail, arp(&wrong))
+            .await
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "--mac is 52:54:00:12:34:56 but 127.0.0.1 answers ARP as 52:54:00:65:43:21"
+        );
+        // nothing to compare against
+        verify_mac_with(&proxy, VerifyMac::Fail, |_| Ok(None))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn ping_flags_reach_the_pings() {
+        let defaults = proxy("127.0.0.1:9", &[]).await;
+        assert_eq!(defaults.ping.options.ttl, 128);
+        assert!(defaults.ping.options.dont_fragment);
+        assert_eq!(defaults.ping.payload, [0xde, 0xad, 0xbe, 0xef]);
+
+        let extra = [
+            "--ping-ttl",
+            "32",
+            "--ping-df",
+            "false",
+            "--ping-payload",
+            "0102",
+        ];
+        let set = proxy("127.0.0.1:9", &extra).await;
+        assert_eq!(set.ping.options.ttl, 32);
+        assert!(!set.ping.options.dont_fragment);
+        assert_eq!(set.ping.payload, [1, 2]);
+
+        // the same flags for the other commands that ping
+        let check_target = ["wol", "check-target", "-t", "127.0.0.1:22"];
+        let relay = ["wol", "relay", "-b", "0.0.0.0:9999", "-k", "relay.key"];
+        let parse = |command: &[&str]| {
+            let args = Args::try_parse_from(command.iter().chain(&extra)).unwrap();
+            match args.command {
+                Some(Command::CheckTarget(args)) => ping_settings(&args.ping),
+                Some(Command::Relay(args)) => ping_settings(&args.ping),
+                _ => panic!("not a command that pings"),
+            }
+        };
+        for settings in [parse(&check_target), parse(&relay)] {
+            assert_eq!(settings.options.ttl, 32);
+            assert!(!settings.options.dont_fragment);
+            assert_eq!(settings.payload, [1, 2]);
+        }
+
+        // a TTL of 0 would never leave the host
+        let ttl = |ttl| Args::try_parse_from(check_target.iter().chain(&["--ping-ttl", ttl]));
+        assert!(ttl("0").is_err());
+        assert!(ttl("1").is_ok());
+    }
+
+    #[test]
+    fn hour_window_contains() {
+        let time = |s| NaiveTime::parse_from_str(s, "%H:%M").unwrap();
+        let day = parse_hours("09:00-17:30").unwrap();
+        assert!(day.contains(time("09:00")));
+        assert!(day.contains(time("17:29")));
+        assert!(!day.contains(time("17:30")));
+        assert!(!day.contains(time("08:59")));
+
+        // past midnight
+        let night = parse_hours("22:00-06:00").unwrap();
+        assert!(night.contains(time("22:00")));
+        assert!(night.contains(time("23:59")));
+        assert!(night.contains(time("00:00")));
+        assert!(night.contains(time("05:59")));
+        assert!(!night.contains(time("06:00")));
+        assert!(!night.contains(time("12:00")));
+
+        assert!(parse_hours("09:00").is_err());
+        assert!(parse_hours("9-17").is_err());
+    }
+
+    /// A wake history without a state file, holding `latencies_ms`.
+    fn history(latencies_ms: &[u64]) -> WakeHistory {
+        let mut history = WakeHistory::load(None).unwrap();
+        for &ms in latencies_ms {
+            history.record(Duration::from_millis(ms));
+        }
+        history
+    }
+
+    #[test]
+    fn wake_history_p90() {
+        assert_eq!(history(&[]).p90(), None);
+        assert_eq!(history(&[1000, 2000]).p90(), None);
+        assert_eq!(
+            history(&[3000, 1000, 2000]).p90(),
+            Some(Duration::from_millis(3000))
+        );
+        let ten: Vec<u64> = (1..=10).rev().map(|s| s * 1000).collect();
+        assert_eq!(history(&ten).p90(), Some(Duration::from_millis(9000)));
+        // only the latest WAKE_HISTORY_LEN wakes count
+        let mut many = vec![60_000; 5];
+        many.extend(&ten);
+        assert_eq!(history(&many).p90(), Some(Duration::from_millis(9000)));
+    }
+
+    #[test]
+    fn wake_history_state_file() {
+        let path = std::env::temp_dir().join(format!("wol-proxy-{}.state", std::process::id()));
+        let tmp = path.with_extension("state.tmp");
+        let _ = std::fs::remove_file(&path);
+        let saved =
+            || -> State { serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap() };
+        let ms = Duration::from_millis;
+
+        let mut history = WakeHistory::load(Some(path.clone())).unwrap();
+        history.record(ms(1000));
+        let older = history.snapshot(false);
+        history.record(ms(2000));
+        history.snapshot(false).save().unwrap();
+        // saved as it goes, but not marked as written on shutdown
+        assert_eq!(saved().wake_latencies_ms, [1000, 2000]);
+        // a save that lost the race to a newer one is dropped
+        older.save().unwrap();
+        assert_eq!(saved().wake_latencies_ms, [1000, 2000]);
+        assert!(!saved().clean_shutdown);
+        assert!(!tmp.exists());
+        // which is what a crash leaves behind, and it is still loaded
+        let reloaded = WakeHistory::load(Some(path.clone())).unwrap();
+        assert_eq!(reloaded.latencies, [ms(1000), ms(2000)]);
+        history.snapshot(true).save().unwrap();
+        assert!(saved().clean_shutdown);
+
+        // a failed write leaves the old file as it was
+        std::fs::create_dir(&tmp).unwrap();
+        history.record(ms(3000));
+        assert!(history.snapshot(false).save().is_err());
+        std::fs::remove_dir(&tmp).unwrap();
+        assert_eq!(saved().wake_latencies_ms, [1000, 2000]);
+
+        // only the latest WAKE_HISTORY_LEN wakes are kept
+        let long = State {
+            wake_latencies_ms: (1..=15).collect(),
+            clean_shutdown: true,
+        };
+        std::fs::write(&path, serde_json::to_vec(&long).unwrap()).unwrap();
+        let loaded = WakeHistory::load(Some(path.clone())).unwrap();
+        assert_eq!(loaded.latencies.len(), WAKE_HISTORY_LEN);
+        assert_eq!(loaded.latencies.front(), Some(&ms(6)));
+
+        std::fs::write(&path, "{").unwrap();
+        let error = WakeHistory::load(Some(path.clone())).err().unwrap();
+        assert!(error.to_string().starts_with("invalid state file"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ping_permission_denied() {
+        let os_error = |code: i32, message: &str| PingError::OsError(code as u32, message.into());
+        let error = ping_permission(&os_error(libc::EPERM, "Operation not permitted"))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("(Operation not permitted)"));
+        assert!(error.contains("--wake-detect tcp"));
+        assert!(ping_permission(&os_error(libc::EACCES, "Permission denied")).is_err());
+        // failures that aren't about permission are left alone
+        assert!(ping_permission(&os_error(libc::ENETUNREACH, "Network is unreachable")).is_ok());
+        assert!(ping_permission(&PingError::TimedOut).is_ok());
+    }
+
+    #[test]
+    fn ping_payload() {
+        assert_eq!(
+            parse_ping_payload("deadbeef").unwrap().0,
+            [0xde, 0xad, 0xbe, 0xef]
+        );
+        assert_eq!(parse_ping_payload("00FF").unwrap().0, [0x00, 0xff]);
+        assert!(parse_ping_payload("").unwrap().0.is_empty());
+        assert!(parse_ping_payload("abc").is_err());
+        assert!(parse_ping_payload("zz").is_err());
+        let longest = "ab".repeat(MAX_PING_PAYLOAD);
+        assert_eq!(
+            parse_ping_payload(&longest).unwrap().0.len(),
+            MAX_PING_PAYLOAD
+        );
+        assert!(parse_ping_payload(&format!("{}ab", longest)).is_err());
+
+        // IPv6 targets take less
+        let v4 = SocketAddr::from(([192, 0, 2, 1], 22));
+        let v6 = SocketAddr::from((Ipv6Addr::LOCALHOST, 22));
+        let payload = |len| vec![0; len];
+        check_ping_payload(&payload(MAX_PING_PAYLOAD), &[v4]).unwrap();
+        check_ping_payload(&payload(MAX_PING_PAYLOAD_V6), &[v4, v6]).unwrap();
+        assert!(check_ping_payload(&payload(MAX_PING_PAYLOAD_V6 + 1), &[v4, v6]).is_err());
+    }
+
+    #[test]
+    fn wake_history_stats() {
+        let stats = history(&[]).stats();
+        assert_eq!(stats["count"], 0);
+        assert!(stats["min_ms"].is_null() && stats["p95_ms"].is_null());
+
+        let stats = history(&[4000, 1000, 2500]).stats();
+        assert_eq!(stats["count"], 3);
+        assert_eq!(stats["min_ms"], 1000);
+        assert_eq!(stats["mean_ms"], 2500);
+        assert_eq!(stats["max_ms"], 4000);
+        assert_eq!(stats["p95_ms"], 4000);
+
+        let ten: Vec<u64> = (1..=10).map(|s| s * 1000).collect();
+        assert_eq!(history(&ten).stats()["p95_ms"], 10_000);
+
+        let mut history = history(&ten);
+        history.reset();
+        assert_eq!(history.stats()["count"], 0);
+    }
+
+    #[test]
+    fn circuit_breaker_opens_after_threshold() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        assert!(!breaker.record(1, false));
+        assert!(!breaker.record(2, false));
+        // a success starts the count again
+        assert!(!breaker.record(3, true));
+        assert!(!breaker.record(4, false));
+        assert!(!breaker.record(5, false));
+        assert!(breaker.allow(6));
+        assert!(breaker.record(6, false));
+        assert!(!breaker.allow(7));
+        // further failures while open don't open it again
+        assert!(!breaker.record(8, false));
+    }
+
+    #[test]
+    fn circuit_breaker_half_open() {
+        // reset at once, so it goes half-open straight away
+        let breaker = CircuitBreaker::new(1, Duration::ZERO);
+        assert!(breaker.record(1, false));
+        // only one trial connection at a time
+        assert!(breaker.allow(2));
+        assert!(!breaker.allow(3));
+        // a failed trial opens it again
+        assert!(breaker.record(2, false));
+        assert!(breaker.allow(4));
+        // only the trial itself can give up its place
+        breaker.abandon(1);
+        assert!(!breaker.allow(5));
+        // nor does a connection from before the trial decide it
+        assert!(!breaker.record(1, false));
+        assert!(!breaker.allow(5));
+        // an abandoned trial lets another through
+        breaker.abandon(4);
+        assert!(breaker.allow(6));
+        // and a successful one closes it
+        assert!(!breaker.record(6, true));
+        assert!(breaker.allow(7));
+        assert!(breaker.allow(8));
+    }
+
+    /// Collects what a fmt subscriber writes, to check the log lines.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_connections_keep_their_ids() {
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .without_time()
+            .finish();
+        let output = || String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let guard = tracing::subscriber::set_default(subscriber);
+        let mut first = run(&awake().await, &["--wake-detect", "tcp"]).await;
+        let mut second = TcpStream::connect(first.peer_addr().unwrap())
+            .await
+            .unwrap();
+        async fn ping(conn: &mut TcpStream) {
+            conn.write_all(b"ping").await.unwrap();
+            let mut reply = [0u8; 4];
+            conn.read_exact(&mut reply).await.unwrap();
+        }
+        // both open at once, taking turns
+        ping(&mut first).await;
+        ping(&mut second).await;
+        ping(&mut first).await;
+        let ports = [&first, &second].map(|conn| conn.local_addr().unwrap().port());
+        drop((first, second));
+        let closed = async {
+            while output().matches("Connection closed").count() < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), closed)
+            .await
+            .unwrap();
+        drop(guard);
+
+        let output = output();
+        let lines: Vec<_> = output.lines().collect();
+        // each connection's lines carry its own id and peer
+        for (id, port, sent) in [(1, ports[0], 8), (2, ports[1], 4)] {
+            let span = format!("connection{{id={} peer=127.0.0.1:{}}}", id, port);
+            let closed = format!("Connection closed (warm start, {} bytes to server", sent);
+            let own = lines.iter().filter(|line| line.contains(&span));
+            assert_eq!(own.filter(|line| line.contains(&closed)).count(), 1);
+        }
+        assert!(lines
+            .iter()
+            .filter(|line| line.contains("Connection closed"))
+            .all(|line| line.contains("connection{id=")));
+    }
+
+    #[tokio::test]
+    async fn no_log_connections_turns_routine_lines_down() {
+        for (quiet, level) in [(false, "INFO"), (true, "DEBUG")] {
+            let captured = Captured::default();
+            let writer = captured.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .with_writer(move || writer.clone())
+                .with_ansi(false)
+                .without_time()
+                .with_max_level(tracing::Level::DEBUG)
+                .finish();
+            let mut extra = vec!["--wake-detect", "tcp"];
+            if quiet {
+                extra.push("--no-log-connections");
+            }
+            let proxy = Arc::new(proxy(&awake().await, &extra).await);
+            let guard = tracing::subscriber::set_default(subscriber);
+            round_trip(&proxy).await.unwrap();
+            drop(guard);
+
+            let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+            let routine: Vec<_> = output
+                .lines()
+                .filter(|line| {
+                    line.contains("Proxying connection") || line.contains("Connection closed")
+                })
+                .collect();
+            assert_eq!(routine.len(), 2, "{}", output);
+            assert!(routine
+                .iter()
+                .all(|line| line.trim_start().starts_with(level)));
+        }
+    }
+
+    #[tokio::test]
+    async fn monitor_follows_the_server() {
+        let target = asleep().await;
+        let proxy = Arc::new(proxy(&target, &["--wake-detect", "tcp"]).await);
+        tokio::spawn(monitor(proxy.clone(), Duration::from_millis(50)));
+        let seen = |up| {
+            let proxy = proxy.clone();
+            async move {
+                while *proxy.known_up.lock().unwrap() != Some(up) {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            }
+        };
+        let timeout = Duration::from_secs(5);
+        tokio::time::timeout(timeout, seen(false)).await.unwrap();
+        assert!(!proxy.is_up().await);
+        let listener = TcpListener::bind(&target).await.unwrap();
+        let server = tokio::spawn(echo(listener));
+        tokio::time::timeout(timeout, seen(true)).await.unwrap();
+        // connections go straight through, without a magic packet
+        assert_eq!(round_trip(&proxy).await.unwrap(), Served::Proxied);
+        assert_eq!(proxy.totals.wakes_sent.load(Ordering::SeqCst), 0);
+        server.abort();
+        let _ = server.await;
+        tokio::time::timeout(timeout, seen(false)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rewakes_when_connecting_fails() {
+        let target = asleep().await;
+        sleeper(&target, Duration::ZERO).await;
+        let extra = [
+            "--wake-detect",
+            "tcp",
+            "--rewake-on-connect-fail",
+            "--connect-retry",
+            "0",
+        ];
+        let proxy = Arc::new(proxy(&target, &extra).await);
+        // --monitor-interval last saw the server up, so no magic packet is
+        // sent before connecting, and connecting fails
+        *proxy.known_up.lock().unwrap() = Some(true);
+        assert_eq!(round_trip(&proxy).await.unwrap(), Served::Proxied);
+        // the wake sequence sent one, which let the retry through
+        assert_eq!(proxy.totals.wakes_sent.load(Ordering::SeqCst), 1);
+        assert_eq!(proxy.totals.wakes_confirmed.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn re_resolved_address_is_kept() {
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let at =
+            |listener: &TcpListener| format!("localhost:{}", listener.local_addr().unwrap().port());
+        let mut proxy = proxy(&at(&first), &["--connect-retry", "0"]).await;
+        // where the server was before it moved
+        let old = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        *proxy.target_addrs.write().unwrap() = vec![old.local_addr().unwrap()];
+        drop(old);
+
+        let (conn, _) = proxy.connect_retrying(Instant::now()).await.unwrap();
+        assert_eq!(conn.peer_addr().unwrap(), first.local_addr().unwrap());
+        assert_eq!(proxy.target_addr(), first.local_addr().unwrap());
+
+        // the next connection goes to the same address without resolving
+        // again, even though the name now points elsewhere
+        proxy.re_resolve = Some(at(&second));
+        let (conn, _) = proxy.connect_retrying(Instant::now()).await.unwrap();
+        assert_eq!(conn.peer_addr().unwrap(), first.local_addr().unwrap());
+
+        // until connecting there fails
+        drop(first);
+        let (conn, _) = proxy.connect_retrying(Instant::now()).await.unwrap();
+        assert_eq!(conn.peer_addr().unwrap(), second.local_addr().unwrap());
+        assert_eq!(proxy.target_addr(), second.local_addr().unwrap());
+    }
+
+    #[test]
+    fn send_on_each_interface() {
+        let interfaces: Vec<String> = ["eth0", "down", "eth1"].map(String::from).into();
+        let tried = Mutex::new(Vec::new());
+        let send = |interface: &String| {
+            tried.lock().unwrap().push(interface.clone());
+            match interface.as_str() {
+                "down" => bail!("no such device"),
+                _ => Ok(()),
+            }
+        };
+        // one packet each, and one interface failing doesn't stop the rest
+        send_on_each(&interfaces, String::as_str, send).unwrap();
+        assert_eq!(*tried.lock().unwrap(), interfaces);
+        // it's only an error when none of them worked
+        let down = ["down".to_string()];
+        assert!(send_on_each(&down, String::as_str, send).is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_avoiding_recent_ports() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let recent = Mutex::new(VecDeque::new());
+        let mut ports = Vec::new();
+        for _ in 0..RECENT_PORTS + 1 {
+            let conn = connect_avoiding(addr, &recent).await.unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            let port = conn.local_addr().unwrap().port();
+            assert_eq!(accepted.peer_addr().unwrap().port(), port);
+            // closed straight away, leaving the port in TIME_WAIT
+            drop(conn);
+            ports.push(port);
+        }
+        let distinct: std::collections::HashSet<_> = ports.iter().collect();
+        assert_eq!(distinct.len(), ports.len());
+        // only the latest are remembered
+        assert_eq!(Vec::from(recent.into_inner().unwrap()), ports[1..]);
+    }
+
+    #[tokio::test]
+    async fn fallback_has_its_own_wake_cap() {
+        let capped = ["--max-wakes-per-hour", "1"];
+        let primary = proxy("127.0.0.1:9", &capped).await;
+        let mut fallback = proxy("127.0.0.1:10", &capped).await;
+        fallback.share_limits(&primary);
+        primary.record_wake().unwrap();
+        assert!(primary.record_wake().is_err());
+        // the primary's wake didn't use up the fallback's
+        fallback.record_wake().unwrap();
+        assert!(fallback.record_wake().is_err());
+    }
+
+    #[test]
+    fn shutdown_summary() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+
+        let totals = Totals::default();
+        assert_eq!(
+            totals.summary(0, Duration::from_millis(1500)),
+            "Accepted 0 connections, proxied 0 B in / 0 B out, 0 WoL events, \
+             avg wake latency n/a, 0 errors. Uptime: 1s."
+        );
+        totals.bytes_to_server.store(2048, Ordering::SeqCst);
+        totals.bytes_to_client.store(300, Ordering::SeqCst);
+        totals.wakes_sent.store(3, Ordering::SeqCst);
+        totals.wakes_confirmed.store(2, Ordering::SeqCst);
+        totals.wake_latency_ms.store(9000, Ordering::SeqCst);
+        totals.errors.store(1, Ordering::SeqCst);
+        assert_eq!(
+            totals.summary(4, Duration::from_secs(3700)),
+            "Accepted 4 connections, proxied 2.0 KiB in / 300 B out, 3 WoL events, \
+             avg wake latency 4.5s, 1 errors. Uptime: 1h 1m 40s."
+        );
+    }
+
+    #[tokio::test]
+    async fn fallback_serves_when_primary_never_wakes() {
+        let target = asleep().await;
+        let packets = magic_packets(&target).await;
+        let extra = ["--wake-detect", "tcp", "--timeout", "1s"];
+        let mut primary = proxy(&target, &extra).await;
+        let mut fallback = proxy(&awake().await, &extra).await;
+        fallback.share_limits(&primary);
+        primary.fallback = Some(Arc::new(fallback));
+        let primary = Arc::new(primary);
+
+        assert_eq!(round_trip(&primary).await.unwrap(), Served::Proxied);
+        // the primary was sent a magic packet, but the fallback, already
+        // up, took the connection
+        assert!(magic_packet_within(&packets, Duration::ZERO).await);
+        assert_eq!(primary.totals.wakes_confirmed.load(Ordering::SeqCst), 0);
+        let fallback = primary.fallback.as_ref().unwrap();
+        assert_eq!(fallback.warm_connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn empty_connections_are_absorbed() {
+        let target = asleep().await;
+        let packets = magic_packets(&target).await;
+        let extra = ["--wake-detect", "tcp", "--absorb-empty-connections"];
+        let proxy = Arc::new(proxy(&target, &extra).await);
+        // a client that connects and closes again without sending anything
+        let (stream, peer) = tokio::io::duplex(64);
+        drop(peer);
+        let result = serve(stream, &proxy, &client(), &Arc::default(), Vec::new()).await;
+        assert_eq!(result.as_ref().unwrap(), &Served::Absorbed);
+        assert_eq!(outcome(&result), None);
+        assert!(!magic_packet_within(&packets, Duration::ZERO).await);
+        assert_eq!(proxy.totals.wakes_sent.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn declined_connections_leave_the_breaker_alone() {
+        let extra = ["--wake-detect", "tcp", "--no-wait"];
+        let proxy = Arc::new(proxy(&asleep().await, &extra).await);
+        let (stream, _peer) = tokio::io::duplex(64);
+        let result = serve(stream, &proxy, &client(), &Arc::default(), Vec::new()).await;
+        assert!(result.as_ref().unwrap_err().is::<Declined>());
+        assert_eq!(outcome(&result), None);
+
+        proxy.maintenance.store(true, Ordering::SeqCst);
+        let (stream, _peer) = tokio::io::duplex(64);
+        let result = serve(stream, &proxy, &client(), &Arc::default(), Vec::new()).await;
+        assert!(result.as_ref().unwrap_err().is::<Declined>());
+        assert_eq!(outcome(&result), None);
+
+        // connections that did try the server count either way
+        assert_eq!(outcome(&Ok(Served::Proxied)), Some(true));
+        assert_eq!(
+            outcome(&Err(anyhow!("Server did not wake up in time"))),
+            Some(false)
+        );
+    }
+
+    #[tokio::test]
+    async fn wakes_through_a_relay() {
+        let target = asleep().await;
+        let packets = magic_packets(&target).await;
+        let relay = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap().to_string();
+        let key_file = std::env::temp_dir().join(format!("wol-proxy-{}.key", std::process::id()));
+        std::fs::write(&key_file, "secret\n").unwrap();
+        let key_path = key_file.to_str().unwrap();
+        let extra = [
+            "--wake-detect",
+            "tcp",
+            "--relay-to",
+            &relay_addr,
+            "--relay-key-file",
+            key_path,
+        ];
+        let proxy = proxy(&target, &extra).await;
+        std::fs::remove_file(&key_file).unwrap();
+        let relayed = tokio::spawn(async move {
+            let (mut conn, _) = relay.accept().await.unwrap();
+            let (body, tag) = relay::read_request(&mut conn).await.unwrap();
+            let mut verifier = Verifier::new(b"secret".to_vec(), Duration::from_secs(60));
+            let request = verifier.verify(&body, &tag, relay::unix_time()).unwrap();
+            assert_eq!(request.mac, "52:54:00:12:34:56");
+            let response = WakeResponse {
+                success: true,
+                error: None,
+                latency_ms: None,
             };
+            relay::write_response(&mut conn, &response).await.unwrap();
+            tokio::spawn(echo(TcpListener::bind(target).await.unwrap()));
+        });
+        assert!(proxy.wake().await.unwrap());
+        relayed.await.unwrap();
+        // the relay sent the magic packet, not the proxy
+        assert!(!magic_packet_within(&packets, Duration::ZERO).await);
+        assert_eq!(proxy.totals.wakes_sent.load(Ordering::SeqCst), 1);
+        assert_eq!(proxy.totals.wakes_confirmed.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn wake_chain_moves_on_to_the_next_method() {
+        let target = asleep().await;
+        let packets = magic_packets(&target).await;
+        let esp32 = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/press", esp32.local_addr().unwrap());
+        let extra = [
+            "--wake-detect",
+            "tcp",
+            "--wake-chain",
+            "wol,esp32",
+            "--method-timeout",
+            "300ms",
+            "--esp32-url",
+            &url,
+            "--max-wakes-per-hour",
+            "2",
+        ];
+        let proxy = proxy(&target, &extra).await;
+        let pressed = tokio::spawn(async move {
+            let (mut conn, _) = esp32.accept().await.unwrap();
+            // the magic packet went out first, and didn't wake the server
+            assert!(magic_packet_within(&packets, Duration::ZERO).await);
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                request.push(conn.read_u8().await.unwrap());
+            }
+            assert!(request.starts_with(b"GET /press HTTP/1.0\r\n"));
+            conn.write_all(b"HTTP/1.0 200 OK\r\n\r\n").await.unwrap();
+            tokio::spawn(echo(TcpListener::bind(target).await.unwrap()));
+        });
+        assert!(proxy.wake().await.unwrap());
+        pressed.await.unwrap();
+        assert_eq!(proxy.totals.wakes_sent.load(Ordering::SeqCst), 2);
+        assert_eq!(proxy.totals.wakes_confirmed.load(Ordering::SeqCst), 1);
+        // both methods were one wake as far as the cap is concerned
+        assert_eq!(proxy.wake_times.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn confirm_banner_waits_for_a_slow_banner() {
+        // a service that takes a while to greet each connection
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(500)).await;
+                    let _ = conn.write_all(b"SSH-2.0-test\r\n").await;
+                });
+            }
         });
+        let confirm = [
+            "--confirm-banner",
+            "--banner-regex",
+            "^SSH-",
+            "--connect-retry",
+            "0",
+        ];
+        let start = Instant::now();
+        let (_conn, banner) = proxy(&target, &confirm)
+            .await
+            .connect_retrying(Instant::now())
+            .await
+            .unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        // passed on to the client once it is proxied
+        assert_eq!(banner, b"SSH-2.0-test\r\n");
+
+        // but not waited on for longer than --banner-timeout
+        let mut impatient = confirm.to_vec();
+        impatient.extend(["--banner-timeout", "200ms"]);
+        let error = proxy(&target, &impatient)
+            .await
+            .connect_retrying(Instant::now())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().starts_with("no banner from"));
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn failed_tls_handshakes_are_unauthenticated() {
+        let (cert, key) = (test_data("server.pem"), test_data("server.key"));
+        let extra = [
+            "--wake-detect",
+            "tcp",
+            "--tls-cert",
+            &cert,
+            "--tls-key",
+            &key,
+        ];
+        let proxy = Arc::new(proxy(&awake().await, &extra).await);
+        let front = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // plain text where a ClientHello should be
+        let mut stranger = TcpStream::connect(front.local_addr().unwrap())
+            .await
+            .unwrap();
+        stranger.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let (stream, _) = front.accept().await.unwrap();
+        let result = handle_connection(stream, &proxy, &client(), &Arc::default()).await;
+        let error = result.as_ref().unwrap_err();
+        let unauthenticated = error.downcast_ref::<Unauthenticated>().unwrap();
+        assert!(unauthenticated.0.starts_with("TLS handshake failed: "));
+        assert_eq!(outcome(&result), None);
+    }
+
+    #[tokio::test]
+    async fn silent_clients_dropped_at_handshake_timeout() {
+        let mut extra = vec!["--wake-detect", "tcp", "--handshake-timeout", "300ms"];
+        extra.extend(["--auth-token", "secret"]);
+        #[cfg(feature = "tls")]
+        let (cert, key) = (test_data("server.pem"), test_data("server.key"));
+        #[cfg(feature = "tls")]
+        extra.extend(["--tls-cert", &cert, "--tls-key", &key]);
+        let proxy = Arc::new(proxy(&awake().await, &extra).await);
+        let front = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        // connects, then sends nothing
+        let _silent = TcpStream::connect(front.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = front.accept().await.unwrap();
+        let start = Instant::now();
+        let error = handle_connection(stream, &proxy, &client(), &Arc::default())
+            .await
+            .unwrap_err();
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        // the TLS handshake never finishes or, without TLS, the auth token
+        // never arrives
+        let expected = match cfg!(feature = "tls") {
+            true => "TLS handshake timed out",
+            false => "no auth token sent",
+        };
+        assert_eq!(error.downcast_ref::<Unauthenticated>().unwrap().0, expected);
+    }
+
+    /// Start `proxy`'s control channel, returning a socket connected to it
+    /// to send commands from.
+    async fn control(proxy: &Arc<Proxy>) -> UdpSocket {
+        let control = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let admin = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        admin.connect(control.local_addr().unwrap()).await.unwrap();
+        let verifier = control::Verifier::new(b"secret".to_vec(), Duration::from_secs(60));
+        tokio::spawn(control_channel(proxy.clone(), control, verifier));
+        admin
+    }
+
+    /// Sign and send `command`.
+    async fn send_command(admin: &UdpSocket, command: &str) {
+        let packet = control::sign(b"secret", command, relay::unix_time());
+        admin.send(packet.as_bytes()).await.unwrap();
+    }
+
+    /// The next reply from the control channel.
+    async fn control_reply(admin: &UdpSocket) -> String {
+        let mut reply = [0u8; 512];
+        let received = tokio::time::timeout(Duration::from_secs(5), admin.recv(&mut reply));
+        let len = received.await.unwrap().unwrap();
+        String::from_utf8_lossy(&reply[..len]).into_owned()
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn handshake_timeout_covers_tls_and_auth_token() {
+        let (cert, key) = (test_data("server.pem"), test_data("server.key"));
+        let extra = [
+            "--wake-detect",
+            "tcp",
+            "--handshake-timeout",
+            "2s",
+            "--auth-token",
+            "secret",
+            "--tls-cert",
+            &cert,
+            "--tls-key",
+            &key,
+        ];
+        let proxy = Arc::new(proxy(&awake().await, &extra).await);
+        let front = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let conn = TcpStream::connect(front.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = front.accept().await.unwrap();
+        let start = Instant::now();
+        // most of the way through the timeout before the TLS handshake,
+        // then no token at all
+        let slow = async {
+            tokio::time::sleep(Duration::from_millis(1500)).await;
+            tls_handshake(conn, None).await.unwrap()
+        };
+        let handling =
+            async { handle_connection(stream, &proxy, &client(), &Arc::default()).await };
+        let (_conn, result) = tokio::join!(slow, handling);
+        // not given another --handshake-timeout for the token
+        assert!(start.elapsed() < Duration::from_millis(2800));
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<Unauthenticated>().unwrap().0,
+            "no auth token sent"
+        );
+    }
+
+    #[tokio::test]
+    async fn maintenance_mode_refuses_new_connections() {
+        let proxy = Arc::new(proxy(&awake().await, &["--wake-detect", "tcp"]).await);
+        let admin = control(&proxy).await;
+        let send = |command| {
+            let admin = &admin;
+            async move {
+                send_command(admin, command).await;
+                assert_eq!(control_reply(admin).await, "OK");
+            }
+        };
+
+        let peer = "192.0.2.1:1000";
+        // MAINT ON only stops waking
+        send("MAINT ON").await;
+        assert!(!proxy.refuses(1, peer));
+        send("MAINT REFUSE").await;
+        assert!(proxy.refuses(2, peer));
+        send("MAINT OFF").await;
+        assert!(!proxy.refuses(3, peer));
+        assert_eq!(round_trip(&proxy).await.unwrap(), Served::Proxied);
+    }
+
+    #[tokio::test]
+    async fn control_wake_reports_skipped_packets() {
+        let extra = ["--wake-detect", "tcp", "--wol-cooldown", "1h"];
+        let proxy = Arc::new(proxy(&asleep().await, &extra).await);
+        let admin = control(&proxy).await;
+        send_command(&admin, "WAKE").await;
+        assert_eq!(control_reply(&admin).await, "OK");
+        // held back by --wol-cooldown; in lower case so it isn't taken for
+        // a replay of the first
+        send_command(&admin, "wake").await;
+        assert_eq!(control_reply(&admin).await, "SKIPPED cooldown/rate limit");
+        assert_eq!(proxy.totals.wakes_sent.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn control_channel_answers_during_a_wake() {
+        let extra = ["--wake-detect", "tcp", "--wol-rate-limit", "1"];
+        let proxy = Arc::new(proxy(&asleep().await, &extra).await);
+        let admin = control(&proxy).await;
+        send_command(&admin, "WAKE").await;
+        assert_eq!(control_reply(&admin).await, "OK");
+        // waits a minute for --wol-rate-limit to allow it
+        send_command(&admin, "wake").await;
+        send_command(&admin, "STATUS").await;
+        let status: serde_json::Value = serde_json::from_str(&control_reply(&admin).await).unwrap();
+        // not monitored, so not known
+        assert_eq!(status["target"], "unknown");
+        send_command(&admin, "MAINT ON").await;
+        assert_eq!(control_reply(&admin).await, "OK");
+        assert!(proxy.maintenance.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn counts_cold_and_warm_connections() {
+        let target = asleep().await;
+        sleeper(&target, Duration::ZERO).await;
+        let proxy = Arc::new(proxy(&target, &["--wake-detect", "tcp"]).await);
+
+        // the first connection had to wake the server, the second found it up
+        assert_eq!(round_trip(&proxy).await.unwrap(), Served::Proxied);
+        assert_eq!(round_trip(&proxy).await.unwrap(), Served::Proxied);
+        assert_eq!(proxy.cold_connections.load(Ordering::SeqCst), 1);
+        assert_eq!(proxy.warm_connections.load(Ordering::SeqCst), 1);
     }
 }
@@ -0,0 +1,79 @@
+//! Helper subcommands shared by the binaries.
+use anyhow::Result;
+use clap_complete::Shell;
+use std::{fs::File, io::Write, path::PathBuf};
+
+#[derive(clap::Args)]
+#[command(after_long_help = "\
+Installing the completions (replace BIN with the program name):
+  bash:        BIN generate-completions bash > ~/.local/share/bash-completion/completions/BIN
+  zsh:         BIN generate-completions zsh > ~/.zfunc/_BIN
+               (with `fpath+=~/.zfunc` before `compinit` in ~/.zshrc)
+  fish:        BIN generate-completions fish > ~/.config/fish/completions/BIN.fish
+  PowerShell:  BIN generate-completions powershell >> $PROFILE")]
+pub struct CompletionsArgs {
+    /// Shell to generate completions for
+    shell: Shell,
+
+    #[clap(short, long)]
+    /// Write the completions to this file instead of stdout
+    output: Option<PathBuf>,
+}
+
+/// Open `path` for writing, or stdout if no path is given.
+fn output(path: Option<&PathBuf>) -> Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(std::io::stdout()),
+    })
+}
+
+/// Write a completion script for `cmd`.
+pub fn generate_completions(cmd: &mut clap::Command, args: &CompletionsArgs) -> Result<()> {
+    let name = cmd.get_name().to_string();
+    let mut out = output(args.output.as_ref())?;
+    clap_complete::generate(args.shell, cmd, name, &mut out);
+    Ok(())
+}
+
+#[derive(clap::Args)]
+pub struct ManArgs {
+    #[clap(short, long)]
+    /// Write the man page to this file instead of stdout
+    output: Option<PathBuf>,
+}
+
+/// Escape text for use in roff.
+fn roff_escape(text: &str) -> String {
+    text.replace('\\', "\\e").replace('-', "\\-")
+}
+
+/// Write a man page for `cmd`, followed by an EXAMPLES section built from
+/// `(command line, description)` pairs and a SEE ALSO section referencing
+/// the given section 1 pages.
+pub fn generate_man(
+    cmd: clap::Command,
+    args: &ManArgs,
+    examples: &[(&str, &str)],
+    see_also: &[&str],
+) -> Result<()> {
+    let mut out = output(args.output.as_ref())?;
+    // the page documents the binary, even if --version names the package
+    let name = cmd.get_name().to_string();
+    clap_mangen::Man::new(cmd.display_name(name)).render(&mut out)?;
+
+    writeln!(out, ".SH EXAMPLES")?;
+    for (command, description) in examples {
+        writeln!(out, ".TP")?;
+        writeln!(out, "\\fB{}\\fR", roff_escape(command))?;
+        writeln!(out, "{}", roff_escape(description))?;
+    }
+
+    writeln!(out, ".SH \"SEE ALSO\"")?;
+    let refs: Vec<String> = see_also
+        .iter()
+        .map(|page| format!("\\fB{}\\fR(1)", roff_escape(page)))
+        .collect();
+    writeln!(out, "{}", refs.join(", "))?;
+    Ok(())
+}
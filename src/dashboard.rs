@@ -0,0 +1,523 @@
+//! A live dashboard in the terminal for `--tui`: the open connections, new
+//! connections per second over the last minute, whether the target is up,
+//! and the proxy's own log output.
+//!
+//! While the dashboard is up, stdout and stderr are redirected into its
+//! log panel and it is drawn on the controlling terminal instead. Dropping
+//! the [`Screen`] puts everything back.
+use crate::{events::Event, transfer::ByteCounts};
+use anyhow::Result;
+use ratatui::{
+    backend::CrosstermBackend,
+    crossterm::{
+        cursor,
+        event::{self as term_event, KeyCode, KeyModifiers},
+        execute,
+        terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+    },
+    layout::{Constraint, Layout},
+    text::{Line, Text},
+    widgets::{Block, Paragraph, Row, Sparkline, Table},
+    Frame, Terminal,
+};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::File,
+    io::{BufRead, BufReader},
+    os::fd::{AsFd, AsRawFd, OwnedFd},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{broadcast, mpsc};
+
+/// How many updates or log lines may be waiting to be drawn before they
+/// are dropped.
+const QUEUE_LEN: usize = 1024;
+
+/// How many log lines are kept for the log panel.
+const LOG_LEN: usize = 500;
+
+/// How many seconds the sparkline covers.
+const HISTORY_SECS: usize = 60;
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+
+type Tty = Terminal<CrosstermBackend<File>>;
+
+#[derive(Clone)]
+enum Update {
+    Event {
+        connection_id: u64,
+        source_addr: String,
+        event: Event,
+    },
+    /// A connection's data is being copied, counted as it goes
+    Transfer {
+        connection_id: u64,
+        counts: Arc<ByteCounts>,
+    },
+    TargetUp(bool),
+}
+
+/// Sends updates to the dashboard task. Cheap to clone.
+#[derive(Clone)]
+pub struct Dashboard {
+    tx: broadcast::Sender<Update>,
+}
+
+/// The terminal and file descriptors taken over by the dashboard, given
+/// back on drop.
+pub struct Screen {
+    tty: File,
+    terminal: Arc<Mutex<Option<Tty>>>,
+    /// what stdout and stderr pointed at before
+    saved_output: Option<(OwnedFd, OwnedFd)>,
+}
+
+impl Dashboard {
+    /// Take over the terminal and start drawing the dashboard, headed with
+    /// `title`. Must be called from within a tokio runtime.
+    pub fn start(title: String) -> Result<(Self, Screen)> {
+        let tty = File::options().read(true).write(true).open("/dev/tty")?;
+        let mut screen = Screen {
+            tty: tty.try_clone()?,
+            terminal: Arc::new(Mutex::new(None)),
+            saved_output: None,
+        };
+        // from here on, an early return drops the screen, undoing what has
+        // been done so far
+        terminal::enable_raw_mode()?;
+        execute!(screen.tty, EnterAlternateScreen, cursor::Hide)?;
+        *screen.terminal.lock().unwrap() = Some(Terminal::new(CrosstermBackend::new(tty))?);
+        let (log, saved_output) = capture_output()?;
+        screen.saved_output = Some(saved_output);
+
+        let (tx, updates) = broadcast::channel(QUEUE_LEN);
+        tokio::spawn(run(
+            screen.terminal.clone(),
+            State::new(title),
+            updates,
+            log,
+        ));
+        Ok((Self { tx }, screen))
+    }
+
+    /// Show an event for a connection.
+    pub fn record(&self, connection_id: u64, source_addr: String, event: Event) {
+        // fails only once the dashboard has gone
+        let _ = self.tx.send(Update::Event {
+            connection_id,
+            source_addr,
+            event,
+        });
+    }
+
+    /// Show the bytes a connection has carried so far, read from `counts`
+    /// whenever the dashboard is redrawn.
+    pub fn transfer(&self, connection_id: u64, counts: Arc<ByteCounts>) {
+        let _ = self.tx.send(Update::Transfer {
+            connection_id,
+            counts,
+        });
+    }
+
+    /// Show whether the target is up.
+    pub fn target_up(&self, up: bool) {
+        let _ = self.tx.send(Update::TargetUp(up));
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        // taking the terminal stops the dashboard task drawing on it
+        drop(self.terminal.lock().unwrap().take());
+        let _ = execute!(self.tty, LeaveAlternateScreen, cursor::Show);
+        let _ = terminal::disable_raw_mode();
+        if let Some(saved) = &self.saved_output {
+            restore_output(saved);
+        }
+    }
+}
+
+/// Point stdout and stderr at a pipe, returning the lines written to it
+/// and copies of the descriptors they pointed at before.
+fn capture_output() -> Result<(mpsc::Receiver<String>, (OwnedFd, OwnedFd))> {
+    let (reader, writer) = std::io::pipe()?;
+    let saved = (
+        std::io::stdout().as_fd().try_clone_to_owned()?,
+        std::io::stderr().as_fd().try_clone_to_owned()?,
+    );
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // SAFETY: this only replaces fd with another copy of the pipe
+        if unsafe { libc::dup2(writer.as_raw_fd(), fd) } < 0 {
+            let e = std::io::Error::last_os_error();
+            restore_output(&saved);
+            return Err(e.into());
+        }
+    }
+    // the copies on stdout and stderr keep the pipe open
+    drop(writer);
+
+    let (tx, rx) = mpsc::channel(QUEUE_LEN);
+    // ends when stdout and stderr are put back, closing the pipe
+    std::thread::spawn(move || {
+        for line in BufReader::new(reader).lines() {
+            let Ok(line) = line else { return };
+            // a full queue means the dashboard can't keep up; never hold
+            // up whoever is logging
+            let _ = tx.try_send(line);
+        }
+    });
+    Ok((rx, saved))
+}
+
+fn restore_output((stdout, stderr): &(OwnedFd, OwnedFd)) {
+    // SAFETY: this only points stdout and stderr back where they were
+    unsafe {
+        libc::dup2(stdout.as_raw_fd(), libc::STDOUT_FILENO);
+        libc::dup2(stderr.as_raw_fd(), libc::STDERR_FILENO);
+    }
+}
+
+struct Connection {
+    source_addr: String,
+    opened: Instant,
+    state: &'static str,
+    /// once its data is being copied
+    counts: Option<Arc<ByteCounts>>,
+}
+
+struct State {
+    title: String,
+    target_up: Option<bool>,
+    connections: BTreeMap<u64, Connection>,
+    /// new connections in each of the last `HISTORY_SECS` seconds, oldest
+    /// first
+    per_second: VecDeque<u64>,
+    /// when the last entry in `per_second` started
+    second_started: Instant,
+    log: VecDeque<String>,
+}
+
+impl State {
+    fn new(title: String) -> Self {
+        Self {
+            title,
+            target_up: None,
+            connections: BTreeMap::new(),
+            per_second: VecDeque::from(vec![0; HISTORY_SECS]),
+            second_started: Instant::now(),
+            log: VecDeque::new(),
+        }
+    }
+
+    fn apply(&mut self, update: Update) {
+        let (connection_id, source_addr, event) = match update {
+            Update::TargetUp(up) => {
+                self.target_up = Some(up);
+                return;
+            }
+            Update::Transfer {
+                connection_id,
+                counts,
+            } => {
+                if let Some(connection) = self.connections.get_mut(&connection_id) {
+                    connection.counts = Some(counts);
+                }
+                return;
+            }
+            Update::Event {
+                connection_id,
+                source_addr,
+                event,
+            } => (connection_id, source_addr, event),
+        };
+        let state = match event {
+            Event::ConnectionAccepted => {
+                self.tick();
+                if let Some(count) = self.per_second.back_mut() {
+                    *count += 1;
+                }
+                self.connections.insert(
+                    connection_id,
+                    Connection {
+                        source_addr,
+                        opened: Instant::now(),
+                        state: "open",
+                        counts: None,
+                    },
+                );
+                return;
+            }
+            Event::WakeSent { .. } => "waking",
+            Event::WakeConfirmed { .. } => {
+                self.target_up = Some(true);
+                "open"
+            }
+            Event::WakeFailed { .. } => "wake failed",
+            Event::ConnectionClosed {
+                bytes_to_server,
+                bytes_to_client,
+                ..
+            } => {
+                self.connections.remove(&connection_id);
+                if let (Some(sent), Some(received)) = (bytes_to_server, bytes_to_client) {
+                    self.log(format!(
+                        "connection {} from {} closed, {} bytes sent, {} received",
+                        connection_id, source_addr, sent, received
+                    ));
+                }
+                return;
+            }
+        };
+        if let Some(connection) = self.connections.get_mut(&connection_id) {
+            connection.state = state;
+        }
+    }
+
+    fn log(&mut self, line: String) {
+        if self.log.len() == LOG_LEN {
+            self.log.pop_front();
+        }
+        self.log.push_back(line);
+    }
+
+    /// Start a new second in `per_second` for each that has passed.
+    fn tick(&mut self) {
+        while self.second_started.elapsed() >= Duration::from_secs(1) {
+            self.per_second.pop_front();
+            self.per_second.push_back(0);
+            self.second_started += Duration::from_secs(1);
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [header, sparkline, connections, log] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Length(5),
+            Constraint::Percentage(40),
+            Constraint::Fill(1),
+        ])
+        .areas(frame.area());
+
+        let target = match self.target_up {
+            Some(true) => "up",
+            Some(false) => "down",
+            None => "unknown",
+        };
+        let status = format!(
+            "target {} · {} open connections · q to quit",
+            target,
+            self.connections.len()
+        );
+        frame.render_widget(
+            Paragraph::new(status).block(Block::bordered().title(self.title.as_str())),
+            header,
+        );
+
+        // the most recent seconds that fit, oldest on the left
+        let width = sparkline.width.saturating_sub(2) as usize;
+        let skip = self.per_second.len().saturating_sub(width);
+        frame.render_widget(
+            Sparkline::default()
+                .block(Block::bordered().title("New connections per second"))
+                .data(self.per_second.iter().skip(skip)),
+            sparkline,
+        );
+
+        let rows = self.connections.iter().map(|(id, connection)| {
+            let bytes = match &connection.counts {
+                Some(counts) => format!(
+                    "{} sent, {} received",
+                    counts.to_server(),
+                    counts.to_client()
+                ),
+                None => String::new(),
+            };
+            Row::new([
+                id.to_string(),
+                connection.source_addr.clone(),
+                connection.state.to_string(),
+                bytes,
+                humantime::format_duration(Duration::from_secs(
+                    connection.opened.elapsed().as_secs(),
+                ))
+                .to_string(),
+            ])
+        });
+        let widths = [
+            Constraint::Length(6),
+            Constraint::Length(40),
+            Constraint::Length(12),
+            Constraint::Length(32),
+            Constraint::Fill(1),
+        ];
+        frame.render_widget(
+            Table::new(rows, widths)
+                .header(Row::new(["ID", "Source", "State", "Bytes", "Open for"]))
+                .block(Block::bordered().title("Connections")),
+            connections,
+        );
+
+        let height = log.height.saturating_sub(2) as usize;
+        let lines: Vec<Line> = self
+            .log
+            .iter()
+            .skip(self.log.len().saturating_sub(height))
+            .map(|line| Line::raw(line.as_str()))
+            .collect();
+        frame.render_widget(
+            Paragraph::new(Text::from(lines)).block(Block::bordered().title("Log")),
+            log,
+        );
+    }
+}
+
+/// Draw the dashboard until the [`Screen`] takes the terminal back.
+async fn run(
+    terminal: Arc<Mutex<Option<Tty>>>,
+    mut state: State,
+    mut updates: broadcast::Receiver<Update>,
+    mut log: mpsc::Receiver<String>,
+) {
+    let mut redraw = tokio::time::interval(REDRAW_INTERVAL);
+    loop {
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(update) => state.apply(update),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            Some(line) = log.recv() => state.log(line),
+            _ = redraw.tick() => {
+                if quit_pressed() {
+                    // raw mode swallows Ctrl-C, so send the signal it would
+                    // have; the proxy then shuts down as usual
+                    // SAFETY: raise has no preconditions
+                    unsafe { libc::raise(libc::SIGINT) };
+                }
+                state.tick();
+                let mut terminal = terminal.lock().unwrap();
+                let Some(terminal) = terminal.as_mut() else {
+                    return;
+                };
+                let _ = terminal.draw(|frame| state.draw(frame));
+            }
+        }
+    }
+}
+
+/// Whether q or Ctrl-C has been pressed since the last check.
+fn quit_pressed() -> bool {
+    let mut quit = false;
+    while term_event::poll(Duration::ZERO).unwrap_or(false) {
+        if let Ok(term_event::Event::Key(key)) = term_event::read() {
+            quit |= key.code == KeyCode::Char('q')
+                || (key.code == KeyCode::Char('c')
+                    && key.modifiers.contains(KeyModifiers::CONTROL));
+        }
+    }
+    quit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(connection_id: u64, event: Event) -> Update {
+        Update::Event {
+            connection_id,
+            source_addr: "192.0.2.1:1000".to_string(),
+            event,
+        }
+    }
+
+    #[test]
+    fn connections_come_and_go() {
+        let mut state = State::new("test".to_string());
+        state.apply(event(1, Event::ConnectionAccepted));
+        state.apply(event(2, Event::ConnectionAccepted));
+        assert_eq!(state.per_second.back(), Some(&2));
+        assert_eq!(state.connections[&1].state, "open");
+
+        state.apply(event(
+            1,
+            Event::WakeSent {
+                methods: Vec::new(),
+            },
+        ));
+        assert_eq!(state.connections[&1].state, "waking");
+        let counts = Arc::new(ByteCounts::default());
+        state.apply(Update::Transfer {
+            connection_id: 1,
+            counts: counts.clone(),
+        });
+        assert!(Arc::ptr_eq(
+            state.connections[&1].counts.as_ref().unwrap(),
+            &counts
+        ));
+
+        let closed = Event::ConnectionClosed {
+            bytes_to_server: Some(3),
+            bytes_to_client: Some(4),
+            error: None,
+        };
+        state.apply(event(1, closed));
+        assert!(!state.connections.contains_key(&1));
+        assert!(state.connections.contains_key(&2));
+        assert_eq!(
+            state.log.back().unwrap(),
+            "connection 1 from 192.0.2.1:1000 closed, 3 bytes sent, 4 received"
+        );
+        // events for a connection that has gone are ignored
+        state.apply(event(
+            1,
+            Event::WakeFailed {
+                error: String::new(),
+            },
+        ));
+        assert!(!state.connections.contains_key(&1));
+    }
+
+    #[test]
+    fn target_state() {
+        let mut state = State::new("test".to_string());
+        assert_eq!(state.target_up, None);
+        state.apply(Update::TargetUp(false));
+        assert_eq!(state.target_up, Some(false));
+        // a confirmed wake shows it up straight away
+        state.apply(event(1, Event::ConnectionAccepted));
+        state.apply(event(1, Event::WakeConfirmed { latency_ms: 5 }));
+        assert_eq!(state.target_up, Some(true));
+        assert_eq!(state.connections[&1].state, "open");
+    }
+
+    #[test]
+    fn sparkline_rolls_over() {
+        let mut state = State::new("test".to_string());
+        state.apply(event(1, Event::ConnectionAccepted));
+        // three seconds later
+        state.second_started -= Duration::from_secs(3);
+        state.apply(event(2, Event::ConnectionAccepted));
+        assert_eq!(state.per_second.len(), HISTORY_SECS);
+        let recent: Vec<_> = state.per_second.iter().rev().take(4).collect();
+        assert_eq!(recent, [&1, &0, &0, &1]);
+
+        // a minute on, it has all scrolled off
+        state.second_started -= Duration::from_secs(HISTORY_SECS as u64);
+        state.tick();
+        assert_eq!(state.per_second.len(), HISTORY_SECS);
+        assert!(state.per_second.iter().all(|&count| count == 0));
+    }
+
+    #[test]
+    fn log_keeps_the_latest_lines() {
+        let mut state = State::new("test".to_string());
+        for i in 0..LOG_LEN + 1 {
+            state.log(i.to_string());
+        }
+        // the oldest log line makes room
+        assert_eq!(state.log.len(), LOG_LEN);
+        assert_eq!(state.log.front().unwrap(), "1");
+    }
+}
@@ -0,0 +1,46 @@
+//! Command line duration parsing.
+use std::time::Duration;
+
+/// Parse a duration given either as whole seconds (`330`) or as a
+/// human-readable string (`5m30s`, `1h 30m`). Meant for use as a clap
+/// `value_parser`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(Duration::from_secs(secs));
+    }
+    humantime::parse_duration(s)
+        .map_err(|e| format!("{}. Use '5m30s' or '330' for 5 minutes 30 seconds", e))
+}
+
+/// Like [`parse_duration`], but refusing zero, for the interval something
+/// is repeated at.
+pub fn parse_interval(s: &str) -> Result<Duration, String> {
+    match parse_duration(s)? {
+        d if d.is_zero() => Err("must be more than zero".to_string()),
+        d => Ok(d),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seconds_or_human_readable() {
+        assert_eq!(parse_duration("330"), Ok(Duration::from_secs(330)));
+        assert_eq!(parse_duration("5m30s"), Ok(Duration::from_secs(330)));
+        assert_eq!(parse_duration("1h 30m"), Ok(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("0"), Ok(Duration::ZERO));
+        let err = parse_duration("5 minutes-ish").unwrap_err();
+        assert!(err.ends_with("Use '5m30s' or '330' for 5 minutes 30 seconds"));
+        assert!(parse_duration("-5").is_err());
+    }
+
+    #[test]
+    fn intervals_are_not_zero() {
+        assert_eq!(parse_interval("5m"), Ok(Duration::from_secs(300)));
+        assert_eq!(parse_interval("500ms"), Ok(Duration::from_millis(500)));
+        assert!(parse_interval("0").is_err());
+        assert!(parse_interval("0s").is_err());
+    }
+}
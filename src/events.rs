@@ -0,0 +1,260 @@
+//! Connection events, as an append-only JSON lines log or a live
+//! WebSocket stream.
+//!
+//! Lines are written by background tasks so neither ever blocks the proxy;
+//! if a writer falls too far behind, events are dropped.
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use serde::Serialize;
+use std::{fs::OpenOptions, io::Write, net::SocketAddr, path::Path, time::SystemTime};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, mpsc},
+};
+use tokio_tungstenite::tungstenite::Message;
+
+/// How many lines may be waiting to be written before events are dropped.
+const QUEUE_LEN: usize = 1024;
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "event")]
+pub enum Event {
+    ConnectionAccepted,
+    WakeSent {
+        methods: Vec<String>,
+    },
+    WakeConfirmed {
+        latency_ms: u64,
+    },
+    WakeFailed {
+        error: String,
+    },
+    ConnectionClosed {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bytes_to_server: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bytes_to_client: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct Record<'a> {
+    timestamp: String,
+    connection_id: u64,
+    source_addr: &'a str,
+    target_addr: SocketAddr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule_name: Option<&'a str>,
+    #[serde(flatten)]
+    event: Event,
+}
+
+impl<'a> Record<'a> {
+    fn new(
+        connection_id: u64,
+        source_addr: &'a str,
+        target_addr: SocketAddr,
+        rule_name: Option<&'a str>,
+        event: Event,
+    ) -> Self {
+        Self {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            connection_id,
+            source_addr,
+            target_addr,
+            rule_name,
+            event,
+        }
+    }
+}
+
+pub struct EventLog {
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl EventLog {
+    /// Open `path` for appending and start the writer task. Must be called
+    /// from within a tokio runtime.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let (tx, mut rx) = mpsc::channel::<Vec<u8>>(QUEUE_LEN);
+        tokio::task::spawn_blocking(move || {
+            while let Some(line) = rx.blocking_recv() {
+                if let Err(e) = file.write_all(&line) {
+                    tracing::warn!("failed to write event log: {}", e);
+                }
+            }
+        });
+        Ok(Self { tx })
+    }
+
+    /// Queue an event for writing.
+    pub fn record(
+        &self,
+        connection_id: u64,
+        source_addr: &str,
+        target_addr: SocketAddr,
+        rule_name: Option<&str>,
+        event: Event,
+    ) {
+        let record = Record::new(connection_id, source_addr, target_addr, rule_name, event);
+        let Ok(mut line) = serde_json::to_vec(&record) else {
+            return;
+        };
+        line.push(b'\n');
+        // a full queue means the disk can't keep up; drop the event rather
+        // than hold up the connection
+        let _ = self.tx.try_send(line);
+    }
+}
+
+/// Broadcasts events to every connected WebSocket client, one JSON text
+/// message per event.
+pub struct EventStream {
+    tx: broadcast::Sender<String>,
+}
+
+impl EventStream {
+    /// Start accepting WebSocket subscribers on `listener`. Must be called
+    /// from within a tokio runtime.
+    pub fn serve(listener: TcpListener) -> Self {
+        let (tx, _) = broadcast::channel(QUEUE_LEN);
+        let subscribers = tx.clone();
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(subscriber(stream, subscribers.subscribe()));
+                    }
+                    Err(e) => tracing::warn!("failed to accept event subscriber: {}", e),
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    /// Send an event to the current subscribers.
+    pub fn record(
+        &self,
+        connection_id: u64,
+        source_addr: &str,
+        target_addr: SocketAddr,
+        rule_name: Option<&str>,
+        event: Event,
+    ) {
+        let record = Record::new(connection_id, source_addr, target_addr, rule_name, event);
+        if let Ok(message) = serde_json::to_string(&record) {
+            // fails only when nobody is subscribed
+            let _ = self.tx.send(message);
+        }
+    }
+}
+
+/// Forward events to one WebSocket client until it goes away. A client
+/// that can't keep up misses the events it fell behind on.
+async fn subscriber(stream: TcpStream, mut events: broadcast::Receiver<String>) {
+    let Ok(ws) = tokio_tungstenite::accept_async(stream).await else {
+        return;
+    };
+    let (mut sink, mut incoming) = ws.split();
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(message) => {
+                    if sink.send(Message::text(message)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            // anything the client sends is ignored; this only notices it
+            // closing, and lets pings get answered
+            message = incoming.next() => match message {
+                Some(Ok(_)) => continue,
+                _ => return,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn log_appends_json_lines() {
+        let path = std::env::temp_dir().join(format!("wol-proxy-{}.events", std::process::id()));
+        std::fs::write(&path, "earlier\n").unwrap();
+        let log = EventLog::open(&path).unwrap();
+        let target = SocketAddr::from(([192, 0, 2, 10], 22));
+        log.record(1, "192.0.2.1:1000", target, None, Event::ConnectionAccepted);
+        let closed = Event::ConnectionClosed {
+            bytes_to_server: Some(10),
+            bytes_to_client: None,
+            error: None,
+        };
+        log.record(1, "192.0.2.1:1000", target, Some("nas"), closed);
+        // the writer runs in the background
+        let contents = loop {
+            let contents = std::fs::read_to_string(&path).unwrap();
+            if contents.lines().count() == 3 {
+                break contents;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines[0], "earlier");
+        let records: Vec<serde_json::Value> = lines[1..]
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records[0]["event"], "ConnectionAccepted");
+        assert_eq!(records[0]["connection_id"], 1);
+        assert_eq!(records[0]["target_addr"], "192.0.2.10:22");
+        assert!(records[0].get("rule_name").is_none());
+        assert_eq!(records[1]["event"], "ConnectionClosed");
+        assert_eq!(records[1]["rule_name"], "nas");
+        assert_eq!(records[1]["bytes_to_server"], 10);
+        // unknown values are left out rather than written as null
+        assert!(records[1].get("bytes_to_client").is_none());
+        assert!(records[1].get("error").is_none());
+        assert!(humantime::parse_rfc3339(records[1]["timestamp"].as_str().unwrap()).is_ok());
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let stream = EventStream::serve(listener);
+        let mut subscribers = Vec::new();
+        for _ in 0..2 {
+            let conn = TcpStream::connect(addr).await.unwrap();
+            let url = format!("ws://{}/", addr);
+            let (ws, _) = tokio_tungstenite::client_async(url, conn).await.unwrap();
+            subscribers.push(ws);
+        }
+
+        let target = SocketAddr::from(([192, 0, 2, 10], 22));
+        let event = Event::WakeSent {
+            methods: vec!["wol".to_string()],
+        };
+        stream.record(7, "192.0.2.1:1000", target, Some("nas"), event);
+        // each subscriber gets the whole stream
+        for ws in &mut subscribers {
+            let message = ws.next().await.unwrap().unwrap();
+            let record: serde_json::Value =
+                serde_json::from_str(message.to_text().unwrap()).unwrap();
+            assert_eq!(record["event"], "WakeSent");
+            assert_eq!(record["methods"], serde_json::json!(["wol"]));
+            assert_eq!(record["connection_id"], 7);
+            assert_eq!(record["source_addr"], "192.0.2.1:1000");
+            assert_eq!(record["target_addr"], "192.0.2.10:22");
+            assert_eq!(record["rule_name"], "nas");
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Sending magic packets as raw Ethernet frames (EtherType 0x0842).
+//!
+//! This skips IP entirely, so it works on networks without routing or ARP
+//! between the proxy and the target, but needs `CAP_NET_RAW` and is only
+//! available on Linux.
+use anyhow::Result;
+use std::net::Ipv4Addr;
+
+/// The Ethernet broadcast address.
+pub const BROADCAST: [u8; 6] = [0xff; 6];
+
+/// EtherType registered for Wake-on-LAN frames.
+pub const ETHERTYPE_WOL: u16 = 0x0842;
+
+/// Build a complete Ethernet frame carrying a magic packet for `dst`.
+pub fn wol_frame(dst: &[u8; 6], src: &[u8; 6]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(14 + 102);
+    frame.extend_from_slice(dst);
+    frame.extend_from_slice(src);
+    frame.extend_from_slice(&ETHERTYPE_WOL.to_be_bytes());
+    frame.extend_from_slice(wake_on_lan::MagicPacket::new(dst).magic_bytes());
+    frame
+}
+
+/// Send a raw Ethernet frame out of `interface`, addressed to `dst`.
+#[cfg(target_os = "linux")]
+pub fn send_frame(interface: &str, dst: &[u8; 6], ethertype: u16, frame: &[u8]) -> Result<()> {
+    use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+    use std::ffi::CString;
+
+    let name = CString::new(interface)?;
+    let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if ifindex == 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let protocol = Protocol::from(ethertype.to_be() as i32);
+    let socket = Socket::new(Domain::PACKET, Type::RAW, Some(protocol))?;
+
+    let addr = unsafe {
+        let mut storage: libc::sockaddr_storage = std::mem::zeroed();
+        let ll = &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_ll);
+        ll.sll_family = libc::AF_PACKET as u16;
+        ll.sll_protocol = ethertype.to_be();
+        ll.sll_ifindex = ifindex as i32;
+        ll.sll_halen = 6;
+        ll.sll_addr[..6].copy_from_slice(dst);
+        SockAddr::new(
+            storage,
+            std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    };
+    socket.send_to(frame, &addr)?;
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn send_frame(_interface: &str, _dst: &[u8; 6], _ethertype: u16, _frame: &[u8]) -> Result<()> {
+    anyhow::bail!("raw Ethernet frames are only supported on Linux");
+}
+
+/// Send a magic packet for `dst` as a raw Ethernet frame from `src`.
+pub fn send_magic_frame(interface: &str, dst: &[u8; 6], src: &[u8; 6]) -> Result<()> {
+    send_frame(interface, dst, ETHERTYPE_WOL, &wol_frame(dst, src))
+}
+
+/// EtherType for ARP.
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+/// Build a broadcast ARP probe (RFC 5227, sender IP 0.0.0.0) asking who
+/// has `target_ip`. Sending one makes switches on the way re-learn which
+/// port `src` lives on.
+pub fn arp_probe_frame(src: &[u8; 6], target_ip: Ipv4Addr) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(14 + 28);
+    frame.extend_from_slice(&BROADCAST);
+    frame.extend_from_slice(src);
+    frame.extend_from_slice(&ETHERTYPE_ARP.to_be_bytes());
+    // hardware type Ethernet, protocol type IPv4, address lengths 6 and 4
+    frame.extend_from_slice(&[0x00, 0x01, 0x08, 0x00, 6, 4]);
+    // operation: request
+    frame.extend_from_slice(&1u16.to_be_bytes());
+    frame.extend_from_slice(src);
+    frame.extend_from_slice(&Ipv4Addr::UNSPECIFIED.octets());
+    frame.extend_from_slice(&[0u8; 6]);
+    frame.extend_from_slice(&target_ip.octets());
+    frame
+}
+
+/// Broadcast an ARP probe for `target_ip` out of `interface`.
+pub fn send_arp_probe(interface: &str, src: &[u8; 6], target_ip: Ipv4Addr) -> Result<()> {
+    send_frame(
+        interface,
+        &BROADCAST,
+        ETHERTYPE_ARP,
+        &arp_probe_frame(src, target_ip),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    #[test]
+    fn wol_frame_bytes() {
+        let dst = [0xaa, 0xbb, 0xcc, 0x00, 0x00, 0x01];
+        let frame = wol_frame(&dst, &SRC);
+        assert_eq!(frame.len(), 14 + 102);
+        // Ethernet: the target as destination, our source, EtherType 0x0842
+        assert_eq!(frame[..6], dst);
+        assert_eq!(frame[6..12], SRC);
+        assert_eq!(frame[12..14], [0x08, 0x42]);
+        // magic packet: six 0xff bytes, then the target's MAC 16 times
+        assert_eq!(frame[14..20], [0xff; 6]);
+        for copy in frame[20..].chunks(6) {
+            assert_eq!(copy, dst);
+        }
+    }
+
+    #[test]
+    fn arp_probe_bytes() {
+        let frame = arp_probe_frame(&SRC, Ipv4Addr::new(192, 168, 1, 30));
+        #[rustfmt::skip]
+        let expected = [
+            // Ethernet: broadcast destination, source, EtherType ARP
+            0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0x52, 0x54, 0x00, 0x12, 0x34, 0x56,
+            0x08, 0x06,
+            // Ethernet/IPv4, request
+            0x00, 0x01, 0x08, 0x00, 6, 4, 0x00, 0x01,
+            // sender: our MAC, 0.0.0.0 as this is a probe
+            0x52, 0x54, 0x00, 0x12, 0x34, 0x56,
+            0, 0, 0, 0,
+            // target: unknown MAC, the IP asked about
+            0, 0, 0, 0, 0, 0,
+            192, 168, 1, 30,
+        ];
+        assert_eq!(frame, expected);
+    }
+}
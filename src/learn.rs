@@ -0,0 +1,177 @@
+//! Recording when connections arrive, to suggest quiet hours and a
+//! timeout for `--learn`.
+use chrono::{DateTime, Local, Timelike};
+use std::{fmt::Write, time::Duration};
+
+/// Gaps longer than this are between separate sessions rather than
+/// follow-up connections, so they don't count towards the timeout.
+const MAX_FOLLOW_UP: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Default)]
+pub struct Traffic {
+    since: Option<DateTime<Local>>,
+    last: Option<DateTime<Local>>,
+    /// connections seen in each hour of the day, local time
+    per_hour: [u64; 24],
+    /// time between each connection and the one before, if it was
+    /// within `MAX_FOLLOW_UP`
+    follow_ups: Vec<Duration>,
+}
+
+impl Traffic {
+    /// Count a connection that arrived at `at`.
+    pub fn record(&mut self, at: DateTime<Local>) {
+        self.since.get_or_insert(at);
+        if let Some(gap) = self.last.and_then(|last| (at - last).to_std().ok()) {
+            if gap <= MAX_FOLLOW_UP {
+                self.follow_ups.push(gap);
+            }
+        }
+        self.last = Some(at);
+        self.per_hour[at.hour() as usize] += 1;
+    }
+
+    /// The longest run of hours without a connection, as the first quiet
+    /// hour and the hour traffic starts again. Runs may wrap past
+    /// midnight. None if every hour, or none, saw a connection.
+    pub fn quiet_hours(&self) -> Option<(u32, u32)> {
+        if self.per_hour.iter().all(|&n| n == 0) {
+            return None;
+        }
+        let mut best: Option<(usize, usize)> = None;
+        for start in 0..24 {
+            // only start runs just after a busy hour, so each is seen whole
+            if self.per_hour[start] != 0 || self.per_hour[(start + 23) % 24] == 0 {
+                continue;
+            }
+            let len = (0..24)
+                .take_while(|i| self.per_hour[(start + i) % 24] == 0)
+                .count();
+            if best.is_none_or(|(_, best_len)| len > best_len) {
+                best = Some((start, len));
+            }
+        }
+        best.map(|(start, len)| (start as u32, ((start + len) % 24) as u32))
+    }
+
+    /// How long to keep the server up after a connection to catch 90% of
+    /// follow-up connections, rounded up to the minute.
+    pub fn suggested_timeout(&self) -> Option<Duration> {
+        if self.follow_ups.is_empty() {
+            return None;
+        }
+        let mut gaps = self.follow_ups.clone();
+        gaps.sort();
+        let p90 = gaps[(gaps.len() * 9).div_ceil(10) - 1];
+        Some(Duration::from_secs(p90.as_secs().div_ceil(60).max(1) * 60))
+    }
+
+    /// A human readable report: a histogram of connections by hour, and
+    /// the suggestions.
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        let total: u64 = self.per_hour.iter().sum();
+        match self.since {
+            Some(since) => {
+                let _ = writeln!(
+                    out,
+                    "{} connections since {}",
+                    total,
+                    since.format("%Y-%m-%d %H:%M")
+                );
+            }
+            None => out.push_str("No connections seen\n"),
+        }
+        out.push_str("\nConnections per hour (local time):\n");
+        let max = self.per_hour.iter().copied().max().unwrap_or(0).max(1);
+        for (hour, &n) in self.per_hour.iter().enumerate() {
+            let bar = "#".repeat((n * 40).div_ceil(max) as usize);
+            let _ = writeln!(out, "{:02}:00 {:<40} {}", hour, bar, n);
+        }
+        out.push('\n');
+        match self.quiet_hours() {
+            Some((start, end)) => {
+                let _ = writeln!(
+                    out,
+                    "Suggested quiet hours: {:02}:00-{:02}:00 (no connections seen), \
+                     i.e. --allow-hours {:02}:00-{:02}:00",
+                    start, end, end, start
+                );
+            }
+            None if total == 0 => {
+                out.push_str("Suggested quiet hours: not enough connections yet\n")
+            }
+            None => {
+                out.push_str("Suggested quiet hours: none, there is traffic around the clock\n")
+            }
+        }
+        match self.suggested_timeout() {
+            Some(timeout) => {
+                let _ = writeln!(
+                    out,
+                    "Suggested timeout after a connection: {} (90% of follow-up connections came sooner)",
+                    humantime::format_duration(timeout)
+                );
+            }
+            None => {
+                out.push_str("Suggested timeout after a connection: not enough connections yet\n")
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2026, 10, 14, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn seen_at(hours: &[u32]) -> Traffic {
+        let mut traffic = Traffic::default();
+        for &hour in hours {
+            traffic.record(at(hour, 0));
+        }
+        traffic
+    }
+
+    #[test]
+    fn quiet_hours_finds_longest_gap() {
+        // busy 8:00-17:59 with a lunchtime gap; the night is longer
+        let traffic = seen_at(&[8, 9, 10, 11, 13, 14, 15, 16, 17]);
+        assert_eq!(traffic.quiet_hours(), Some((18, 8)));
+    }
+
+    #[test]
+    fn quiet_hours_can_run_past_midnight() {
+        let traffic = seen_at(&[3, 20]);
+        assert_eq!(traffic.quiet_hours(), Some((4, 20)));
+        let traffic = seen_at(&[10]);
+        assert_eq!(traffic.quiet_hours(), Some((11, 10)));
+    }
+
+    #[test]
+    fn quiet_hours_needs_a_gap_and_traffic() {
+        assert_eq!(Traffic::default().quiet_hours(), None);
+        let all: Vec<u32> = (0..24).collect();
+        assert_eq!(seen_at(&all).quiet_hours(), None);
+    }
+
+    #[test]
+    fn suggested_timeout_covers_most_follow_ups() {
+        let mut traffic = Traffic::default();
+        // nine follow-ups a minute apart and one after 30 minutes
+        for minute in 0..10 {
+            traffic.record(at(9, minute));
+        }
+        traffic.record(at(9, 39));
+        // hours apart, so not a follow-up at all
+        traffic.record(at(15, 0));
+        assert_eq!(traffic.suggested_timeout(), Some(Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,22 @@
+//! Shared pieces of the wol-proxy binaries.
+pub mod auth;
+pub mod cli;
+pub mod control;
+#[cfg(all(feature = "tui", unix))]
+pub mod dashboard;
+pub mod duration;
+pub mod events;
+pub mod layer2;
+pub mod learn;
+pub mod logging;
+pub mod mac;
+pub mod net;
+pub mod pidfile;
+pub mod privs;
+pub mod relay;
+pub mod runtime;
+pub mod shutdown;
+pub mod srv;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transfer;
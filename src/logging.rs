@@ -0,0 +1,370 @@
+//! Setting up `tracing` output: the terminal formatter, optionally
+//! colored, or the local syslog daemon.
+//!
+//! Either way the same `RUST_LOG` filtering applies. Coloring happens
+//! here rather than at the call sites, so log lines that aren't going to
+//! a terminal stay plain. Events worth a color of their own are named
+//! after one of the [`kind`]s.
+use anyhow::{anyhow, Result};
+use std::{
+    fmt::{self, Write},
+    io::IsTerminal,
+    str::FromStr,
+    sync::Mutex,
+};
+use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+    filter::Targets,
+    layer::{Context, Layer},
+    prelude::*,
+    registry::LookupSpan,
+};
+
+/// Names for events that are colored by what they are about, given as
+/// `info!(name: kind::WAKE_SENT, ...)`.
+pub mod kind {
+    /// A client connected, or is being proxied to the server
+    pub const CONNECTION: &str = "connection";
+    /// A magic packet was sent or relayed
+    pub const WAKE_SENT: &str = "wake_sent";
+    /// The server came up after being woken
+    pub const WOKE: &str = "woke";
+    /// The wakelock was acquired or released
+    pub const WAKELOCK: &str = "wakelock";
+}
+
+#[derive(clap::Args)]
+pub struct LogArgs {
+    #[clap(long)]
+    /// Log to the local syslog daemon instead of the terminal
+    syslog: bool,
+
+    #[clap(long, default_value = "daemon", value_parser = parse_facility, requires = "syslog")]
+    /// Syslog facility to log under (e.g. "daemon", "local0")
+    syslog_facility: Facility,
+
+    #[clap(long)]
+    /// Never color log output, even on a terminal
+    no_color: bool,
+}
+
+impl LogArgs {
+    /// Never color the output, e.g. because it isn't going straight to
+    /// the terminal after all.
+    pub fn without_color(self) -> Self {
+        Self {
+            no_color: true,
+            ..self
+        }
+    }
+
+    /// Install the global subscriber, logging as `process` if to syslog.
+    pub fn init(&self, process: &str) -> Result<()> {
+        // the same RUST_LOG handling fmt::init does
+        let targets = std::env::var("RUST_LOG")
+            .ok()
+            .and_then(|var| Targets::from_str(&var).ok())
+            .unwrap_or_else(|| Targets::new().with_default(Level::INFO));
+        if self.syslog {
+            let syslog = self.syslog_layer(process)?;
+            tracing_subscriber::registry()
+                .with(syslog)
+                .with(targets)
+                .init();
+            return Ok(());
+        }
+
+        // stdout is left for command output
+        let ansi = !self.no_color && std::io::stderr().is_terminal();
+        let layer = tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_ansi(ansi);
+        #[cfg(feature = "color")]
+        if ansi {
+            let layer = layer.event_format(Colored(tracing_subscriber::fmt::format()));
+            tracing_subscriber::registry()
+                .with(layer)
+                .with(targets)
+                .init();
+            return Ok(());
+        }
+        tracing_subscriber::registry()
+            .with(layer)
+            .with(targets)
+            .init();
+        Ok(())
+    }
+
+    fn syslog_layer(&self, process: &str) -> Result<SyslogLayer> {
+        let formatter = Formatter3164 {
+            facility: self.syslog_facility,
+            hostname: None,
+            process: process.to_string(),
+            pid: std::process::id(),
+        };
+        let logger =
+            syslog::unix(formatter).map_err(|e| anyhow!("failed to connect to syslog: {}", e))?;
+        Ok(SyslogLayer {
+            logger: Mutex::new(logger),
+        })
+    }
+}
+
+fn parse_facility(s: &str) -> Result<Facility, String> {
+    Facility::from_str(s).map_err(|_| format!("unknown syslog facility '{}'", s))
+}
+
+/// Writes each event to syslog, at the severity matching its level,
+/// prefixed with the spans it is in as `name{key=value}: `.
+pub struct SyslogLayer {
+    logger: Mutex<Logger<LoggerBackend, Formatter3164>>,
+}
+
+/// A span's fields, formatted when it is created.
+struct SpanFields(String);
+
+impl<S> Layer<S> for SyslogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut line = Line::default();
+        attrs.record(&mut line);
+        if let Some(span) = ctx.span(id) {
+            let fields = line.fields.trim_start().to_string();
+            span.extensions_mut().insert(SpanFields(fields));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut message = String::new();
+        for span in ctx
+            .event_scope(event)
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+        {
+            message.push_str(span.name());
+            if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                if !fields.is_empty() {
+                    let _ = write!(message, "{{{}}}", fields);
+                }
+            }
+            message.push_str(": ");
+        }
+        let mut line = Line::default();
+        event.record(&mut line);
+        message += &line.message;
+        message += &line.fields;
+        let mut logger = self.logger.lock().unwrap();
+        // there is nowhere left to report a failure to log
+        let _ = match *event.metadata().level() {
+            Level::ERROR => logger.err(message),
+            Level::WARN => logger.warning(message),
+            Level::INFO => logger.info(message),
+            _ => logger.debug(message),
+        };
+    }
+}
+
+/// An event's message followed by its other fields as ` key=value`.
+#[derive(Default)]
+struct Line {
+    message: String,
+    fields: String,
+}
+
+impl Visit for Line {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Colors whole lines by what the event is about, going by its level and
+/// [`kind`], and leaves everything else to the wrapped formatter.
+#[cfg(feature = "color")]
+struct Colored<F>(F);
+
+#[cfg(feature = "color")]
+impl<S, N, F> tracing_subscriber::fmt::FormatEvent<S, N> for Colored<F>
+where
+    S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    N: for<'a> tracing_subscriber::fmt::FormatFields<'a> + 'static,
+    F: tracing_subscriber::fmt::FormatEvent<S, N>,
+{
+    fn format_event(
+        &self,
+        ctx: &tracing_subscriber::fmt::FmtContext<'_, S, N>,
+        mut writer: tracing_subscriber::fmt::format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        use owo_colors::{AnsiColors, OwoColorize};
+
+        let metadata = event.metadata();
+        let color = match (*metadata.level(), metadata.name()) {
+            (Level::ERROR, _) => AnsiColors::Red,
+            (_, kind::WAKELOCK) => AnsiColors::Magenta,
+            (_, kind::CONNECTION) => AnsiColors::Green,
+            (_, kind::WAKE_SENT) => AnsiColors::Yellow,
+            (_, kind::WOKE) => AnsiColors::Cyan,
+            _ => return self.0.format_event(ctx, writer, event),
+        };
+
+        // format without escapes of its own, so the color covers the line.
+        // Span fields were formatted when the span was created, escapes
+        // and all, so those are taken out again.
+        let mut plain = String::new();
+        self.0.format_event(
+            ctx,
+            tracing_subscriber::fmt::format::Writer::new(&mut plain),
+            event,
+        )?;
+        writeln!(writer, "{}", strip_escapes(plain.trim_end()).color(color))
+    }
+}
+
+/// `line` without its ANSI escape sequences.
+#[cfg(feature = "color")]
+fn strip_escapes(line: &str) -> String {
+    let mut plain = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // CSI sequences end at their first letter
+            chars.by_ref().find(char::is_ascii_alphabetic);
+        } else {
+            plain.push(c);
+        }
+    }
+    plain
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    /// A syslog "daemon" on a socket of its own, and a layer sending to it
+    /// under the local0 facility.
+    fn mock_syslog(name: &str) -> (UnixDatagram, SyslogLayer) {
+        let path =
+            std::env::temp_dir().join(format!("wol-proxy-{}-{}.sock", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        let formatter = Formatter3164 {
+            facility: Facility::LOG_LOCAL0,
+            hostname: None,
+            process: "test".to_string(),
+            pid: 1,
+        };
+        let logger = syslog::unix_custom(formatter, &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let layer = SyslogLayer {
+            logger: Mutex::new(logger),
+        };
+        (socket, layer)
+    }
+
+    /// The priority and message of the next record the daemon got.
+    fn received(socket: &UnixDatagram) -> (u8, String) {
+        let mut buf = [0; 1024];
+        let len = socket.recv(&mut buf).unwrap();
+        let record = std::str::from_utf8(&buf[..len]).unwrap();
+        let (priority, rest) = record[1..].split_once('>').unwrap();
+        let (_, message) = rest.split_once("test[1]: ").unwrap();
+        (priority.parse().unwrap(), message.to_string())
+    }
+
+    #[test]
+    fn severity_follows_level() {
+        let (socket, layer) = mock_syslog("severity");
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            tracing::error!("broken");
+            tracing::warn!("odd");
+            tracing::info!(count = 2, "fine");
+            tracing::debug!("detail");
+        });
+        // local0 is facility 16, so priorities are 128 + severity
+        assert_eq!(received(&socket), (131, "broken".to_string()));
+        assert_eq!(received(&socket), (132, "odd".to_string()));
+        assert_eq!(received(&socket), (134, "fine count=2".to_string()));
+        assert_eq!(received(&socket), (135, "detail".to_string()));
+    }
+
+    #[test]
+    fn prefixed_with_spans() {
+        let (socket, layer) = mock_syslog("spans");
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            let connection = tracing::info_span!("connection", id = 1, peer = %"192.0.2.1:1000");
+            connection.in_scope(|| {
+                tracing::info!("accepted");
+                tracing::info_span!("wake").in_scope(|| tracing::info!("sent"));
+            });
+            tracing::info!("outside");
+        });
+        let connection = "connection{id=1 peer=192.0.2.1:1000}";
+        assert_eq!(received(&socket).1, format!("{}: accepted", connection));
+        // outermost first, and no braces for a span without fields
+        assert_eq!(received(&socket).1, format!("{}: wake: sent", connection));
+        assert_eq!(received(&socket).1, "outside");
+    }
+
+    #[cfg(feature = "color")]
+    #[test]
+    fn colored_by_kind() {
+        use std::sync::Arc;
+
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let writer = output.clone();
+        let layer = tracing_subscriber::fmt::layer()
+            .with_writer(move || WriteTo(writer.clone()))
+            .event_format(Colored(
+                tracing_subscriber::fmt::format()
+                    .with_ansi(true)
+                    .without_time()
+                    .with_target(false),
+            ));
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            tracing::info!(name: kind::WAKE_SENT, "Sending magic packet");
+            tracing::error!(name: kind::WAKE_SENT, "failed");
+            // the message alone doesn't decide the color
+            tracing::info!("Sending magic packet");
+        });
+        let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines[0], "\x1b[33m INFO Sending magic packet\x1b[39m");
+        assert_eq!(lines[1], "\x1b[31mERROR failed\x1b[39m");
+        assert!(!lines[2].starts_with("\x1b[33m"));
+    }
+
+    #[cfg(feature = "color")]
+    struct WriteTo(std::sync::Arc<Mutex<Vec<u8>>>);
+
+    #[cfg(feature = "color")]
+    impl std::io::Write for WriteTo {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "color")]
+    #[test]
+    fn escapes_stripped() {
+        let span = "connection{\x1b[3mid\x1b[0m\x1b[2m=\x1b[0m1}: wol: up";
+        assert_eq!(strip_escapes(span), "connection{id=1}: wol: up");
+        assert_eq!(strip_escapes("plain"), "plain");
+    }
+}
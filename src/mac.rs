@@ -0,0 +1,119 @@
+//! MAC address helpers.
+use anyhow::{bail, Result};
+use std::net::Ipv4Addr;
+
+/// Parse a MAC address into a [u8; 6]
+pub fn parse_mac(mac: &str) -> Result<[u8; 6]> {
+    // relay requests carry the MAC from the network, so check it can be
+    // sliced up before doing so
+    if mac.len() != 17 || !mac.is_ascii() {
+        bail!("invalid MAC address: {}", mac);
+    }
+    let mut out = [0u8; 6];
+    for i in 0..6 {
+        out[i] = u8::from_str_radix(&mac[3 * i..(3 * i) + 2], 16)?;
+    }
+    Ok(out)
+}
+
+/// Reject MAC addresses that can't belong to a single machine: multicast
+/// ones (including broadcast) and all zeroes.
+pub fn check_unicast(mac: &[u8; 6]) -> Result<()> {
+    if *mac == [0; 6] {
+        bail!("{} is the all-zero MAC address", format_mac(mac));
+    }
+    if *mac == [0xff; 6] {
+        bail!("{} is the broadcast MAC address", format_mac(mac));
+    }
+    if mac[0] & 1 != 0 {
+        bail!(
+            "{} is a multicast MAC address, not one of a single machine",
+            format_mac(mac)
+        );
+    }
+    Ok(())
+}
+
+/// Format a MAC address as `AA:BB:CC:DD:EE:FF`
+pub fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Read the hardware address of a local network interface (Linux).
+pub fn interface_mac(interface: &str) -> Result<[u8; 6]> {
+    let addr = std::fs::read_to_string(format!("/sys/class/net/{}/address", interface))?;
+    parse_mac(addr.trim())
+}
+
+/// Look up the MAC address the kernel's ARP table has for `ip` (Linux).
+pub fn neighbor_mac(ip: Ipv4Addr) -> Result<Option<[u8; 6]>> {
+    let table = std::fs::read_to_string("/proc/net/arp")?;
+    Ok(parse_arp_table(&table, ip))
+}
+
+/// Find `ip` in the contents of `/proc/net/arp`, ignoring incomplete
+/// entries.
+pub fn parse_arp_table(table: &str, ip: Ipv4Addr) -> Option<[u8; 6]> {
+    // IP address, HW type, Flags, HW address, Mask, Device
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [addr, _, flags, mac, ..] if addr.parse() == Ok(ip) && *flags != "0x0" => {
+                parse_mac(mac).ok()
+            }
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARP_TABLE: &str = "\
+IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.1      0x1         0x2         aa:bb:cc:00:00:01     *        eth0
+192.168.1.20     0x1         0x0         00:00:00:00:00:00     *        eth0
+192.168.1.30     0x1         0x2         52:54:00:12:34:56     *        eth0
+";
+
+    #[test]
+    fn finds_complete_arp_entries() {
+        let ip = |s: &str| s.parse().unwrap();
+        assert_eq!(
+            parse_arp_table(ARP_TABLE, ip("192.168.1.30")),
+            Some([0x52, 0x54, 0x00, 0x12, 0x34, 0x56])
+        );
+        // incomplete entries and missing addresses are both unknown
+        assert_eq!(parse_arp_table(ARP_TABLE, ip("192.168.1.20")), None);
+        assert_eq!(parse_arp_table(ARP_TABLE, ip("192.168.1.40")), None);
+        assert_eq!(parse_arp_table("", ip("192.168.1.1")), None);
+    }
+
+    #[test]
+    fn malformed_macs_are_errors() {
+        assert_eq!(
+            parse_mac("52:54:00:12:34:56").unwrap(),
+            [0x52, 0x54, 0x00, 0x12, 0x34, 0x56]
+        );
+        assert!(parse_mac("").is_err());
+        assert!(parse_mac("52:54:00").is_err());
+        assert!(parse_mac("52:54:00:12:34:zz").is_err());
+        // the right length, but with a slice boundary inside the 'é'
+        assert!(parse_mac("52:54:00:12:34\u{e9}6").is_err());
+    }
+
+    #[test]
+    fn unicast_check() {
+        assert!(check_unicast(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]).is_ok());
+        // locally administered is still unicast
+        assert!(check_unicast(&[0x02, 0, 0, 0, 0, 1]).is_ok());
+        assert!(check_unicast(&[0; 6]).is_err());
+        assert!(check_unicast(&[0xff; 6]).is_err());
+        assert!(check_unicast(&[0x01, 0x00, 0x5e, 0, 0, 1]).is_err());
+        assert!(check_unicast(&[0x33, 0x33, 0, 0, 0, 1]).is_err());
+    }
+}
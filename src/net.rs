@@ -0,0 +1,62 @@
+//! Helpers for dealing with peer addresses.
+use std::net::{IpAddr, SocketAddr};
+
+/// Convert an IPv4-mapped IPv6 peer address (`::ffff:a.b.c.d`, as seen by
+/// listeners bound to `[::]`) to its plain IPv4 form so it logs and
+/// compares like any other IPv4 client.
+pub fn canonical_peer(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// How a client address appears in logs and events: as is, or with `mask`
+/// its host part replaced by `x` (the last octet of IPv4, the last 64 bits
+/// of IPv6), for logs shared with people who shouldn't see who connected.
+pub fn show_client(addr: SocketAddr, mask: bool) -> String {
+    if !mask {
+        return addr.to_string();
+    }
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{}.{}.{}.x:{}", a, b, c, addr.port())
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, d, ..] = ip.segments();
+            format!("[{:x}:{:x}:{:x}:{:x}:x]:{}", a, b, c, d, addr.port())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_host_part() {
+        let v4: SocketAddr = "192.168.1.23:5022".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8:1:2:3:4:5:6]:22".parse().unwrap();
+        assert_eq!(show_client(v4, false), "192.168.1.23:5022");
+        assert_eq!(show_client(v4, true), "192.168.1.x:5022");
+        assert_eq!(show_client(v6, false), "[2001:db8:1:2:3:4:5:6]:22");
+        assert_eq!(show_client(v6, true), "[2001:db8:1:2:x]:22");
+        // mapped IPv4 clients mask like plain ones once made canonical
+        let mapped: SocketAddr = "[::ffff:10.0.0.7]:80".parse().unwrap();
+        assert_eq!(show_client(canonical_peer(mapped), true), "10.0.0.x:80");
+    }
+
+    #[test]
+    fn unmaps_ipv4_mapped_peers() {
+        let mapped: SocketAddr = "[::ffff:192.168.1.23]:5022".parse().unwrap();
+        assert_eq!(canonical_peer(mapped), "192.168.1.23:5022".parse().unwrap());
+        // anything else is left alone, including the IPv4-compatible form
+        for addr in [
+            "192.168.1.23:5022",
+            "[2001:db8::1]:22",
+            "[::1]:22",
+            "[::192.168.1.23]:22",
+        ] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            assert_eq!(canonical_peer(addr), addr);
+        }
+    }
+}
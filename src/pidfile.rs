@@ -0,0 +1,51 @@
+//! PID files for init systems that track daemons that way.
+use anyhow::Result;
+use std::path::PathBuf;
+
+/// A PID file containing the current process ID, removed again when this
+/// is dropped.
+pub struct PidFile {
+    path: PathBuf,
+    /// PID found in a file left behind by an earlier run, if any
+    pub stale: Option<String>,
+}
+
+impl PidFile {
+    /// Write the PID file, replacing any existing one. The file is written
+    /// next to its final location and renamed into place, so readers never
+    /// see it half-written.
+    pub fn create(path: PathBuf) -> Result<Self> {
+        let stale = std::fs::read_to_string(&path)
+            .ok()
+            .map(|contents| contents.trim().to_string());
+
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, format!("{}\n", std::process::id()))?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(Self { path, stale })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn written_and_removed() {
+        let path = std::env::temp_dir().join(format!("wol-proxy-{}.pid", std::process::id()));
+        std::fs::write(&path, "12345\n").unwrap();
+        let pid_file = PidFile::create(path.clone()).unwrap();
+        assert_eq!(pid_file.stale.as_deref(), Some("12345"));
+        let pid = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(pid, format!("{}\n", std::process::id()));
+        drop(pid_file);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,131 @@
+//! Dropping root privileges once the listening sockets are bound.
+//!
+//! This lets the proxies listen on ports below 1024 when started as root
+//! and then carry on as an unprivileged user. Only available on Linux.
+//! Anything needing privileges later on, such as raw Ethernet wakes,
+//! stops working once they are dropped.
+use anyhow::Result;
+
+#[derive(clap::Args)]
+pub struct PrivilegeArgs {
+    #[clap(long)]
+    /// Switch to this user after binding the listening socket. Any
+    /// --pid-file is written as this user.
+    user: Option<String>,
+
+    #[clap(long)]
+    /// Switch to this group after binding the listening socket (defaults to
+    /// the primary group of --user)
+    group: Option<String>,
+
+    #[clap(long)]
+    /// Set PR_SET_NO_NEW_PRIVS so the process can never regain privileges
+    no_new_privs: bool,
+}
+
+/// Drop privileges as requested in `args`. Call this after binding, but
+/// before writing the pid file.
+#[cfg(target_os = "linux")]
+pub fn drop_privileges(args: &PrivilegeArgs) -> Result<()> {
+    use anyhow::{anyhow, bail};
+    use nix::unistd::{
+        getegid, geteuid, getgid, getuid, setgid, setgroups, setuid, Group, Uid, User,
+    };
+
+    let user = match &args.user {
+        Some(name) => {
+            Some(User::from_name(name)?.ok_or_else(|| anyhow!("no such user: {}", name))?)
+        }
+        None => None,
+    };
+    let gid = match (&args.group, &user) {
+        (Some(name), _) => Some(
+            Group::from_name(name)?
+                .ok_or_else(|| anyhow!("no such group: {}", name))?
+                .gid,
+        ),
+        (None, Some(user)) => Some(user.gid),
+        (None, None) => None,
+    };
+
+    // the group has to go first: once the user is switched we're no
+    // longer allowed to change it
+    if let Some(gid) = gid {
+        setgroups(&[])?;
+        setgid(gid)?;
+        if getgid() != gid || getegid() != gid {
+            bail!("failed to switch to group {}", gid);
+        }
+    }
+    if let Some(user) = &user {
+        setuid(user.uid)?;
+        // if root can be regained, the drop didn't take
+        if getuid() != user.uid
+            || geteuid() != user.uid
+            || (!user.uid.is_root() && setuid(Uid::from_raw(0)).is_ok())
+        {
+            bail!("failed to drop privileges to user {}", user.name);
+        }
+    }
+    if args.no_new_privs {
+        nix::sys::prctl::set_no_new_privs()?;
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn drop_privileges(args: &PrivilegeArgs) -> Result<()> {
+    if args.user.is_some() || args.group.is_some() || args.no_new_privs {
+        anyhow::bail!("dropping privileges is only supported on Linux");
+    }
+    Ok(())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use nix::unistd::{geteuid, getuid, setuid, Uid};
+    use std::process::Command;
+
+    /// Set in the environment of the child `switches_user` runs.
+    const CHILD: &str = "WOL_PROXY_PRIVS_CHILD";
+
+    fn user(name: &str) -> PrivilegeArgs {
+        PrivilegeArgs {
+            user: Some(name.to_string()),
+            group: None,
+            no_new_privs: true,
+        }
+    }
+
+    #[test]
+    fn unknown_user() {
+        let error = drop_privileges(&user("wol-proxy-no-such-user")).unwrap_err();
+        assert_eq!(error.to_string(), "no such user: wol-proxy-no-such-user");
+    }
+
+    /// Root can't be got back once it's dropped, so this happens in a
+    /// fresh run of the test binary rather than a fork of the
+    /// multi-threaded harness. Only runs as root.
+    #[test]
+    fn switches_user() {
+        if std::env::var_os(CHILD).is_some() {
+            drop_privileges(&user("nobody")).unwrap();
+            assert!(!getuid().is_root());
+            assert!(setuid(Uid::from_raw(0)).is_err());
+            return;
+        }
+        if !geteuid().is_root() {
+            return;
+        }
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "privs::tests::switches_user", "--test-threads=1"])
+            .env(CHILD, "1")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        // and not because the filter matched nothing
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+    }
+}
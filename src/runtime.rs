@@ -0,0 +1,78 @@
+//! Building the tokio runtime the binaries run on.
+//!
+//! A single thread is the default and is plenty for a handful of connections:
+//! tokio makes tasks yield after a budget of socket operations, so a busy
+//! transfer can't keep a new connection's wake detection waiting for long.
+//! With `--worker-threads`, transfers and wake detection can also run in
+//! parallel, at the cost of that many threads and some cross-thread
+//! synchronisation on every connection.
+use anyhow::Result;
+use tokio::runtime::{Builder, Runtime};
+
+/// A multi-threaded runtime with `worker_threads` workers if given,
+/// otherwise a single-threaded one.
+pub fn build(worker_threads: Option<u32>) -> Result<Runtime> {
+    let mut builder = match worker_threads {
+        Some(threads) => {
+            let mut builder = Builder::new_multi_thread();
+            builder.worker_threads(threads as usize);
+            builder
+        }
+        None => Builder::new_current_thread(),
+    };
+    Ok(builder.enable_all().build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    /// How long a wake check may take to connect while a transfer is
+    /// saturating the runtime. Far above what either runtime needs, so it
+    /// only catches the check being starved outright.
+    const MAX_PROBE_LATENCY: Duration = Duration::from_millis(500);
+
+    /// Time a TCP connect, as wake detection does, while another task is
+    /// copying as fast as loopback allows.
+    async fn probe_during_transfer() -> Duration {
+        let source = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let source_addr = source.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = source.accept().await.unwrap();
+            let chunk = vec![0u8; 64 * 1024];
+            while stream.write_all(&chunk).await.is_ok() {}
+        });
+        tokio::spawn(async move {
+            let mut stream = TcpStream::connect(source_addr).await.unwrap();
+            let _ = tokio::io::copy(&mut stream, &mut tokio::io::sink()).await;
+        });
+        // let the transfer get going
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        tokio::spawn(async move { while target.accept().await.is_ok() {} });
+        let start = Instant::now();
+        TcpStream::connect(target_addr).await.unwrap();
+        start.elapsed()
+    }
+
+    // These build their own runtimes rather than using #[tokio::test], as
+    // the runtimes `build` makes are what is being tested.
+    #[test]
+    fn probe_not_starved_on_current_thread() {
+        let latency = build(None).unwrap().block_on(probe_during_transfer());
+        assert!(latency < MAX_PROBE_LATENCY, "took {:?}", latency);
+    }
+
+    #[test]
+    fn probe_not_starved_on_worker_threads() {
+        let latency = build(Some(2)).unwrap().block_on(probe_during_transfer());
+        assert!(latency < MAX_PROBE_LATENCY, "took {:?}", latency);
+    }
+}
@@ -0,0 +1,22 @@
+//! Waiting for the process to be asked to stop.
+use anyhow::Result;
+
+/// Resolve once SIGINT or SIGTERM (Ctrl-C on other platforms) arrives.
+#[cfg(unix)]
+pub async fn signal() -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = interrupt.recv() => (),
+        _ = terminate.recv() => (),
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn signal() -> Result<()> {
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
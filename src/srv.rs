@@ -0,0 +1,122 @@
+//! Finding the target through a DNS SRV record (RFC 2782).
+use anyhow::{bail, Result};
+use hickory_resolver::{
+    proto::rr::{rdata::SRV, RData, RecordType},
+    TokioResolver,
+};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+};
+
+/// A resolver using the system's DNS configuration.
+pub fn system_resolver() -> Result<TokioResolver> {
+    Ok(TokioResolver::builder_tokio()?.build()?)
+}
+
+/// Look up the SRV record `name` with `resolver`, pick one of its targets
+/// and resolve that to addresses carrying the record's port.
+pub async fn resolve(resolver: &TokioResolver, name: &str) -> Result<Vec<SocketAddr>> {
+    let lookup = resolver.lookup(name, RecordType::SRV).await?;
+    let records: Vec<&SRV> = lookup
+        .answers()
+        .iter()
+        .filter_map(|record| match &record.data {
+            RData::SRV(srv) => Some(srv),
+            _ => None,
+        })
+        .collect();
+    let Some(srv) = select(&records, RandomState::new().build_hasher().finish()) else {
+        bail!("{} has no SRV records", name);
+    };
+    // a target of "." means the service is decidedly not available
+    if srv.target.is_root() {
+        bail!("{} says the service is not available", name);
+    }
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for ip in resolver.lookup_ip(srv.target.clone()).await?.iter() {
+        let addr = SocketAddr::new(ip, srv.port);
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+    if addrs.is_empty() {
+        bail!("{} did not resolve to any addresses", srv.target);
+    }
+    Ok(addrs)
+}
+
+/// Choose among `records` the way RFC 2782 asks: only the lowest priority
+/// is considered, and within it each record is picked in proportion to its
+/// weight. `random` supplies the randomness.
+pub fn select<'a>(records: &[&'a SRV], random: u64) -> Option<&'a SRV> {
+    let priority = records.iter().map(|srv| srv.priority).min()?;
+    let candidates: Vec<&SRV> = records
+        .iter()
+        .copied()
+        .filter(|srv| srv.priority == priority)
+        .collect();
+    let total: u64 = candidates.iter().map(|srv| u64::from(srv.weight)).sum();
+    if total == 0 {
+        return Some(candidates[(random % candidates.len() as u64) as usize]);
+    }
+    let mut pick = random % total;
+    for srv in candidates {
+        let weight = u64::from(srv.weight);
+        if pick < weight {
+            return Some(srv);
+        }
+        pick -= weight;
+    }
+    unreachable!("pick is below the total weight")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::rr::Name;
+
+    fn srv(priority: u16, weight: u16, target: &str) -> SRV {
+        SRV::new(priority, weight, 22, Name::from_ascii(target).unwrap())
+    }
+
+    fn picked(records: &[SRV], random: u64) -> String {
+        let records: Vec<&SRV> = records.iter().collect();
+        select(&records, random).unwrap().target.to_ascii()
+    }
+
+    #[test]
+    fn only_lowest_priority_is_considered() {
+        let records = [srv(20, 100, "backup."), srv(10, 1, "primary.")];
+        for random in 0..50 {
+            assert_eq!(picked(&records, random), "primary.");
+        }
+    }
+
+    #[test]
+    fn picks_in_proportion_to_weight() {
+        let records = [
+            srv(10, 1, "light."),
+            srv(10, 3, "heavy."),
+            srv(10, 0, "none."),
+        ];
+        let heavy = (0..400)
+            .filter(|&random| picked(&records, random) == "heavy.")
+            .count();
+        assert_eq!(heavy, 300);
+        assert!((0..400).all(|random| picked(&records, random) != "none."));
+    }
+
+    #[test]
+    fn zero_weights_are_picked_evenly() {
+        let records = [srv(10, 0, "a."), srv(10, 0, "b.")];
+        assert_eq!(picked(&records, 0), "a.");
+        assert_eq!(picked(&records, 1), "b.");
+    }
+
+    #[test]
+    fn no_records() {
+        assert!(select(&[], 0).is_none());
+    }
+}
@@ -0,0 +1,111 @@
+//! Counting the bytes a proxied connection carries while it is still open,
+//! rather than only once the copy has finished.
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Bytes carried so far each way on one connection.
+#[derive(Default)]
+pub struct ByteCounts {
+    to_server: AtomicU64,
+    to_client: AtomicU64,
+}
+
+impl ByteCounts {
+    /// Bytes read from the client, to be sent on to the server.
+    pub fn to_server(&self) -> u64 {
+        self.to_server.load(Ordering::Relaxed)
+    }
+
+    /// Bytes written to the client.
+    pub fn to_client(&self) -> u64 {
+        self.to_client.load(Ordering::Relaxed)
+    }
+
+    /// Count bytes carried without going through a [`Counted`] stream.
+    pub fn add(&self, to_server: u64, to_client: u64) {
+        self.to_server.fetch_add(to_server, Ordering::Relaxed);
+        self.to_client.fetch_add(to_client, Ordering::Relaxed);
+    }
+}
+
+/// A client stream that counts what is read from and written to it.
+pub struct Counted<S> {
+    inner: S,
+    counts: Arc<ByteCounts>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, counts: Arc<ByteCounts>) -> Self {
+        Self { inner, counts }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.counts.to_server.fetch_add(read, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            self.counts
+                .to_client
+                .fetch_add(written as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn counts_both_ways() {
+        let (client, mut peer) = tokio::io::duplex(64);
+        let counts = Arc::new(ByteCounts::default());
+        let mut counted = Counted::new(client, counts.clone());
+
+        peer.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(counted.read(&mut buf).await.unwrap(), 5);
+        counted.write_all(b"hi").await.unwrap();
+        assert_eq!((counts.to_server(), counts.to_client()), (5, 2));
+
+        // nothing more is counted at EOF
+        drop(peer);
+        assert_eq!(counted.read(&mut buf).await.unwrap(), 0);
+        assert_eq!((counts.to_server(), counts.to_client()), (5, 2));
+    }
+}
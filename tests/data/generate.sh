@@ -0,0 +1,37 @@
+#!/bin/sh
+# Regenerate the TLS test certificates: a CA, a server certificate for
+# localhost and a client certificate signed by it, and a self-signed client
+# certificate the CA knows nothing about.
+set -e
+cd "$(dirname "$0")"
+days=36500
+
+key() {
+    openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out "$1.key"
+}
+
+# sign NAME CN EXTENSIONS: a certificate for NAME.key, signed by the CA
+sign() {
+    printf 'basicConstraints = critical, CA:FALSE\nkeyUsage = critical, digitalSignature\n%s\n' \
+        "$3" >"$1.ext"
+    openssl req -new -key "$1.key" -subj "/CN=$2" -out "$1.csr"
+    openssl x509 -req -in "$1.csr" -CA ca.pem -CAkey ca.key -CAcreateserial -days $days \
+        -extfile "$1.ext" -out "$1.pem"
+    rm "$1.csr" "$1.ext"
+}
+
+key ca
+openssl req -new -x509 -key ca.key -subj "/CN=wol-proxy test CA" -days $days \
+    -addext "basicConstraints = critical, CA:TRUE" \
+    -addext "keyUsage = critical, keyCertSign" -out ca.pem
+key server
+sign server localhost "extendedKeyUsage = serverAuth
+subjectAltName = DNS:localhost, IP:127.0.0.1"
+key client
+sign client client "extendedKeyUsage = clientAuth"
+key untrusted
+openssl req -new -x509 -key untrusted.key -subj "/CN=untrusted" -days $days \
+    -addext "basicConstraints = critical, CA:FALSE" \
+    -addext "extendedKeyUsage = clientAuth" -out untrusted.pem
+# nothing else is to be signed by the CA
+rm ca.key ca.srl